@@ -0,0 +1,370 @@
+use std::time::Duration;
+
+use {sys, clock, faults, trace, Instant};
+use condvar::yield_lock;
+
+/// A condition variable which can be used with any kind of lock.
+///
+/// `Condvar` can only be waited on with the guard of a `Mutex`. This type
+/// instead accepts the guard of any lock implementing `RawLock`, including the
+/// read and write guards of `RWLock`. In exchange for this flexibility each
+/// `AnyCondvar` contains an internal mutex which is taken on every wait and
+/// notification, so it is somewhat slower than a `Condvar`.
+///
+/// Unlike `Condvar`, an `AnyCondvar` is not bound to a single lock and may be
+/// used with different locks over time.
+///
+/// # Example
+///
+/// ```
+/// use std::sync::Arc;
+/// use sync::{RWLock, AnyCondvar};
+///
+/// let pair = Arc::new((RWLock::new(false), AnyCondvar::new()));
+/// let pair2 = pair.clone();
+///
+/// spawn(proc() {
+///     let &(ref lock, ref cvar) = &*pair2;
+///     *lock.write() = true;
+///     cvar.notify_all();
+/// });
+///
+/// // wait for the thread to set the flag, only holding the lock for reading
+/// let &(ref lock, ref cvar) = &*pair;
+/// let started = lock.read();
+/// while !*started {
+///     cvar.wait(&started);
+/// }
+/// ```
+pub struct AnyCondvar { inner: Box<StaticAnyCondvar> }
+
+/// Statically allocated condition variables which can be used with any lock.
+///
+/// This structure is identical to `AnyCondvar` except that it is suitable for
+/// use in static initializers for other structures.
+///
+/// # Example
+///
+/// ```
+/// use sync::{StaticAnyCondvar, ANY_CONDVAR_INIT};
+///
+/// static CVAR: StaticAnyCondvar = ANY_CONDVAR_INIT;
+/// ```
+pub struct StaticAnyCondvar {
+    lock: sys::Mutex,
+    cvar: sys::Condvar,
+}
+
+/// Constant initializer for a statically allocated `AnyCondvar`.
+pub const ANY_CONDVAR_INIT: StaticAnyCondvar = StaticAnyCondvar {
+    lock: sys::MUTEX_INIT,
+    cvar: sys::CONDVAR_INIT,
+};
+
+/// A lock guard which can be released and re-acquired in place, allowing it to
+/// be waited on with an `AnyCondvar`.
+///
+/// This is implemented by the guards of `Mutex` and `RWLock`.
+pub trait RawLock {
+    /// Releases the lock held by this guard without consuming the guard.
+    ///
+    /// This is unsafe as the guard will still exist, but the lock will not be
+    /// held until `relock` is called.
+    unsafe fn unlock(&self);
+
+    /// Re-acquires a lock which was previously released with `unlock`.
+    ///
+    /// Implementations should panic if the lock has become poisoned in the
+    /// meantime, just as they would when first acquiring the lock.
+    unsafe fn relock(&self);
+}
+
+impl AnyCondvar {
+    /// Creates a new condition variable which is ready to be waited on and
+    /// notified.
+    pub fn new() -> AnyCondvar {
+        let mut inner = box StaticAnyCondvar {
+            lock: unsafe { sys::Mutex::new() },
+            cvar: unsafe { sys::Condvar::new() },
+        };
+        unsafe { inner.cvar.init() }
+        AnyCondvar { inner: inner }
+    }
+
+    /// Block the current thread until this condition variable receives a
+    /// notification.
+    ///
+    /// This function will atomically release the lock held by `guard` and
+    /// block the current thread, re-acquiring the lock before returning. Like
+    /// `Condvar::wait`, this function is susceptible to spurious wakeups.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the lock was poisoned while this thread
+    /// was waiting, in the same situations as acquiring the lock would.
+    pub fn wait<T: RawLock>(&self, guard: &T) {
+        unsafe {
+            let me: &'static AnyCondvar = &*(self as *const _);
+            me.inner.wait(guard)
+        }
+    }
+
+    /// Wait on this condition variable for a notification, timing out after a
+    /// specified duration.
+    ///
+    /// Returns `false` if the wait timed out, and `true` otherwise. See
+    /// `Condvar::wait_timeout`.
+    pub fn wait_timeout<T: RawLock>(&self, guard: &T, dur: Duration) -> bool {
+        unsafe {
+            let me: &'static AnyCondvar = &*(self as *const _);
+            me.inner.wait_timeout(guard, dur)
+        }
+    }
+
+    /// Wait on this condition variable for a notification, timing out once a
+    /// specified point in time has been reached.
+    ///
+    /// Returns `false` if the wait timed out, and `true` otherwise. See
+    /// `Condvar::wait_deadline`.
+    pub fn wait_deadline<T: RawLock>(&self, guard: &T,
+                                     deadline: Instant) -> bool {
+        unsafe {
+            let me: &'static AnyCondvar = &*(self as *const _);
+            me.inner.wait_deadline(guard, deadline)
+        }
+    }
+
+    /// Wake up one blocked thread on this condvar.
+    ///
+    /// See `Condvar::notify_one`.
+    pub fn notify_one(&self) {
+        unsafe {
+            let me: &'static AnyCondvar = &*(self as *const _);
+            me.inner.notify_one()
+        }
+    }
+
+    /// Wake up all blocked threads on this condvar.
+    ///
+    /// See `Condvar::notify_all`.
+    pub fn notify_all(&self) {
+        unsafe {
+            let me: &'static AnyCondvar = &*(self as *const _);
+            me.inner.notify_all()
+        }
+    }
+}
+
+impl Drop for AnyCondvar {
+    fn drop(&mut self) {
+        unsafe {
+            self.inner.cvar.destroy();
+            self.inner.lock.destroy();
+        }
+    }
+}
+
+impl StaticAnyCondvar {
+    /// Block the current thread until this condition variable receives a
+    /// notification.
+    ///
+    /// See `AnyCondvar::wait`.
+    pub fn wait<T: RawLock>(&'static self, guard: &T) {
+        unsafe {
+            self.wait_with(guard, "AnyCondvar::wait", || {
+                if faults::spurious_wakeup() {
+                    yield_lock(&self.lock);
+                } else {
+                    self.cvar.wait(&self.lock);
+                }
+                true
+            });
+        }
+    }
+
+    /// Wait on this condition variable for a notification, timing out after a
+    /// specified duration.
+    ///
+    /// See `AnyCondvar::wait_timeout`.
+    pub fn wait_timeout<T: RawLock>(&'static self, guard: &T,
+                                    dur: Duration) -> bool {
+        unsafe {
+            self.wait_with(guard, "AnyCondvar::wait_timeout", || {
+                if faults::spurious_wakeup() {
+                    yield_lock(&self.lock);
+                    true
+                } else if faults::timeout() {
+                    yield_lock(&self.lock);
+                    false
+                } else {
+                    clock::wait_timeout(&self.cvar, &self.lock, dur)
+                }
+            })
+        }
+    }
+
+    /// Wait on this condition variable for a notification, timing out once a
+    /// specified point in time has been reached.
+    ///
+    /// See `AnyCondvar::wait_deadline`.
+    pub fn wait_deadline<T: RawLock>(&'static self, guard: &T,
+                                     deadline: Instant) -> bool {
+        unsafe {
+            self.wait_with(guard, "AnyCondvar::wait_deadline", || {
+                if faults::spurious_wakeup() {
+                    yield_lock(&self.lock);
+                    true
+                } else if faults::timeout() {
+                    yield_lock(&self.lock);
+                    false
+                } else {
+                    clock::wait_deadline(&self.cvar, &self.lock, deadline)
+                }
+            })
+        }
+    }
+
+    /// Wake up one blocked thread on this condvar.
+    ///
+    /// See `AnyCondvar::notify_one`.
+    pub fn notify_one(&'static self) {
+        trace::instant("AnyCondvar::notify_one", &self.cvar);
+        unsafe {
+            self.lock.lock();
+            self.cvar.notify_one();
+            self.lock.unlock();
+        }
+    }
+
+    /// Wake up all blocked threads on this condvar.
+    ///
+    /// See `AnyCondvar::notify_all`.
+    pub fn notify_all(&'static self) {
+        trace::instant("AnyCondvar::notify_all", &self.cvar);
+        unsafe {
+            self.lock.lock();
+            self.cvar.notify_all();
+            self.lock.unlock();
+        }
+    }
+
+    /// Deallocate all resources associated with this static condvar.
+    ///
+    /// This method is unsafe to call as there is no guarantee that there are no
+    /// active users of the condvar, and this also doesn't prevent any future
+    /// users of the condvar. This method is required to be called to not leak
+    /// memory on all platforms.
+    pub unsafe fn destroy(&'static self) {
+        self.cvar.destroy();
+        self.lock.destroy();
+    }
+
+    // The internal mutex is acquired before the user's lock is released, and
+    // notifications take the internal mutex, so a notification sent after the
+    // user's lock is released cannot be lost before this thread is waiting.
+    unsafe fn wait_with<T: RawLock>(&self, guard: &T, name: &'static str,
+                                    f: || -> bool) -> bool {
+        self.lock.lock();
+        guard.unlock();
+        trace::begin(name, &self.cvar);
+        let ret = f();
+        trace::end(name, &self.cvar);
+        self.lock.unlock();
+        guard.relock();
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use super::{AnyCondvar, StaticAnyCondvar, ANY_CONDVAR_INIT};
+    use {Mutex, RWLock};
+
+    #[test]
+    fn smoke() {
+        let c = AnyCondvar::new();
+        c.notify_one();
+        c.notify_all();
+    }
+
+    #[test]
+    fn static_smoke() {
+        static C: StaticAnyCondvar = ANY_CONDVAR_INIT;
+        C.notify_one();
+        C.notify_all();
+        unsafe { C.destroy(); }
+    }
+
+    #[test]
+    fn mutex() {
+        let pair = Arc::new((Mutex::new(false), AnyCondvar::new()));
+        let pair2 = pair.clone();
+        spawn(proc() {
+            let &(ref lock, ref cvar) = &*pair2;
+            *lock.lock() = true;
+            cvar.notify_one();
+        });
+
+        let &(ref lock, ref cvar) = &*pair;
+        let g = lock.lock();
+        while !*g {
+            cvar.wait(&g);
+        }
+    }
+
+    #[test]
+    fn rwlock() {
+        let pair = Arc::new((RWLock::new(0u), AnyCondvar::new()));
+        let (tx, rx) = channel();
+        for _ in range(0u, 2) {
+            let pair2 = pair.clone();
+            let tx = tx.clone();
+            spawn(proc() {
+                let &(ref lock, ref cvar) = &*pair2;
+                let g = lock.read();
+                tx.send(());
+                while *g == 0 {
+                    cvar.wait(&g);
+                }
+                tx.send(());
+            });
+        }
+
+        rx.recv();
+        rx.recv();
+        let &(ref lock, ref cvar) = &*pair;
+        *lock.write() = 1;
+        cvar.notify_all();
+        rx.recv();
+        rx.recv();
+    }
+
+    #[test]
+    fn wait_timeout() {
+        let l = RWLock::new(());
+        let c = AnyCondvar::new();
+        let g = l.write();
+        assert!(!c.wait_timeout(&g, Duration::milliseconds(1)));
+    }
+
+    #[test]
+    #[should_fail]
+    fn poisoned() {
+        let pair = Arc::new((RWLock::new(()), AnyCondvar::new()));
+        let pair2 = pair.clone();
+
+        let &(ref lock, ref cvar) = &*pair;
+        let g = lock.write();
+        spawn(proc() {
+            let &(ref lock, ref cvar) = &*pair2;
+            let _g = lock.write();
+            cvar.notify_one();
+            panic!();
+        });
+        // relocking will eventually panic once the other thread has poisoned
+        // the lock
+        loop { cvar.wait(&g); }
+    }
+}
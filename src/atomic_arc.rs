@@ -0,0 +1,378 @@
+use std::mem;
+use std::os;
+use std::task;
+
+use {trace, sharded, Arc};
+use sharded::CACHE_LINE;
+use sys::atomic;
+
+/// A shared `Arc<T>` which can be atomically loaded and replaced without
+/// taking a lock.
+///
+/// This is useful for data such as configuration which is read often and
+/// replaced wholesale from time to time. Readers get their own reference to
+/// the current value with `load`, which never blocks, and keep using it for as
+/// long as they like while writers install new values with `store`, `swap` or
+/// `compare_and_swap`.
+///
+/// # Reclamation
+///
+/// A reader may be in the middle of taking a reference to a value at the same
+/// moment as a writer replaces it. While it does so, the reader publishes the
+/// value in one of a number of slots, spread over the CPUs of the system so
+/// that readers don't contend with each other. A replaced value which isn't
+/// published in any slot is released by the writer straight away. Otherwise it
+/// is retired, and released by the last reader which had published it as soon
+/// as that reader is done. Values handed out by `load` are ordinary `Arc`s, and
+/// stay alive for as long as the reader holds them.
+///
+/// # Example
+///
+/// ```
+/// use std::sync::Arc;
+/// use sync::AtomicArc;
+///
+/// let config = Arc::new(AtomicArc::new(Arc::new("initial")));
+/// let config2 = config.clone();
+///
+/// spawn(proc() {
+///     config2.store(Arc::new("updated"));
+/// });
+///
+/// let current = config.load();
+/// assert!(*current == "initial" || *current == "updated");
+/// ```
+pub struct AtomicArc<T> {
+    // The node holding the current value, never null.
+    current: atomic::AtomicPtr<Node<T>>,
+    // The nodes which readers are in the middle of reading, see `Slot`.
+    slots: Vec<Slot<T>>,
+    // A stack of replaced nodes which were still published by a reader.
+    retired: atomic::AtomicPtr<Node<T>>,
+}
+
+struct Node<T> {
+    value: Arc<T>,
+    // Only used once the node has been retired.
+    next: *mut Node<T>,
+}
+
+// A slot is null when free, `CLAIMED` once a reader has taken it, and then
+// holds the node which that reader is reading. Slots are padded out to more
+// than a cache line so that readers using neighbouring slots don't contend on
+// the same line.
+struct Slot<T> {
+    node: atomic::AtomicPtr<Node<T>>,
+    _pad: [u64, ..CACHE_LINE / 8],
+}
+
+// Each CPU gets a couple of slots, so a reader which is preempted while holding
+// one doesn't hold up others on the same CPU.
+const SLOTS_PER_CPU: uint = 2;
+
+fn claimed<T>() -> *mut Node<T> { 1 as *mut Node<T> }
+
+impl<T: Send + Sync> AtomicArc<T> {
+    /// Creates a new `AtomicArc` holding `value`.
+    pub fn new(value: Arc<T>) -> AtomicArc<T> {
+        let slots = Vec::from_fn(os::num_cpus() * SLOTS_PER_CPU, |_| Slot {
+            node: atomic::AtomicPtr::new(0 as *mut Node<T>),
+            _pad: [0, ..CACHE_LINE / 8],
+        });
+        AtomicArc {
+            current: atomic::AtomicPtr::new(node(value)),
+            slots: slots,
+            retired: atomic::AtomicPtr::new(0 as *mut Node<T>),
+        }
+    }
+
+    /// Returns a reference to the current value.
+    ///
+    /// This does not take any lock. It only has to retry if a writer replaces
+    /// the value at the same moment, or if every slot is in use by readers
+    /// which have been preempted.
+    pub fn load(&self) -> Arc<T> {
+        let slot = self.claim();
+        let node = self.protect(slot);
+        let ret = unsafe { (*node).value.clone() };
+        self.release(slot);
+        ret
+    }
+
+    /// Replaces the current value with `value`.
+    pub fn store(&self, value: Arc<T>) {
+        drop(self.swap(value));
+    }
+
+    /// Replaces the current value with `value`, returning the previous value.
+    pub fn swap(&self, value: Arc<T>) -> Arc<T> {
+        trace::instant("AtomicArc::swap", self);
+        let old = self.current.swap(node(value), atomic::SeqCst);
+        // Nothing else can release the old node until it has been retired, so
+        // this thread can safely take out its own reference to it first.
+        let ret = unsafe { (*old).value.clone() };
+        self.retire(old);
+        ret
+    }
+
+    /// Replaces the current value with `new` if the current value is
+    /// `current`, as determined by pointer equality.
+    ///
+    /// The previous value is returned either way. The replacement took place
+    /// if and only if the returned value points to the same data as
+    /// `current`.
+    pub fn compare_and_swap(&self, current: &Arc<T>, new: Arc<T>) -> Arc<T> {
+        trace::instant("AtomicArc::compare_and_swap", self);
+        let new = node(new);
+        // The node being compared against stays published while it's swapped
+        // out, so its address can't be reused by another node in between.
+        let slot = self.claim();
+        let mut node = self.protect(slot);
+        loop {
+            let prev = unsafe { (*node).value.clone() };
+            if !same(&prev, current) {
+                self.release(slot);
+                unsafe {
+                    drop(mem::transmute::<*mut Node<T>, Box<Node<T>>>(new))
+                }
+                return prev
+            }
+            let actual = self.current.compare_and_swap(node, new,
+                                                       atomic::SeqCst);
+            if actual == node {
+                self.release(slot);
+                self.retire(node);
+                return prev
+            }
+            // Another writer got in first, so compare against whatever it
+            // installed instead.
+            node = self.protect(slot);
+        }
+    }
+}
+
+impl<T> AtomicArc<T> {
+    // Claims a free slot, starting with those of the calling thread's CPU.
+    fn claim(&self) -> &atomic::AtomicPtr<Node<T>> {
+        let start = sharded::current() * SLOTS_PER_CPU;
+        loop {
+            for i in range(0, self.slots.len()) {
+                let slot = &self.slots[(start + i) % self.slots.len()].node;
+                if slot.compare_and_swap(0 as *mut Node<T>, claimed(),
+                                         atomic::SeqCst).is_null() {
+                    return slot
+                }
+            }
+            task::deschedule();
+        }
+    }
+
+    // Publishes the current node in `slot`, returning it. The node won't be
+    // released until the slot is released.
+    fn protect(&self, slot: &atomic::AtomicPtr<Node<T>>) -> *mut Node<T> {
+        let mut node = self.current.load(atomic::SeqCst);
+        loop {
+            slot.store(node, atomic::SeqCst);
+            // The node may have been replaced and released before it was
+            // published, in which case its replacement is tried instead. Once
+            // it's seen to still be current after being published, any writer
+            // replacing it will find it in the slot.
+            let actual = self.current.load(atomic::SeqCst);
+            if actual == node { return node }
+            node = actual;
+        }
+    }
+
+    // Frees `slot`, and releases any retired nodes which it was the last to
+    // publish.
+    fn release(&self, slot: &atomic::AtomicPtr<Node<T>>) {
+        slot.store(0 as *mut Node<T>, atomic::SeqCst);
+        // A writer retiring a node pushes it before checking the slots, and
+        // this checks the stack after clearing the slot, so either the writer
+        // saw the slot cleared or this sees the node.
+        if !self.retired.load(atomic::SeqCst).is_null() {
+            self.reclaim();
+        }
+    }
+
+    // Retires a node which has been replaced, releasing it right away if no
+    // reader has it published.
+    fn retire(&self, node: *mut Node<T>) {
+        unsafe { self.push_retired(node, node) }
+        self.reclaim();
+    }
+
+    // Releases every retired node which isn't published in any slot.
+    fn reclaim(&self) {
+        loop {
+            let mut head = self.retired.swap(0 as *mut Node<T>, atomic::SeqCst);
+            let mut kept = Vec::new();
+            while !head.is_null() {
+                let next = unsafe { (*head).next };
+                if self.published(head) {
+                    kept.push(head);
+                } else {
+                    let _node: Box<Node<T>> = unsafe { mem::transmute(head) };
+                }
+                head = next;
+            }
+            if kept.len() == 0 { return }
+            unsafe {
+                for w in kept.windows(2) {
+                    (*w[0]).next = w[1];
+                }
+                self.push_retired(kept[0], kept[kept.len() - 1]);
+            }
+            // A reader which cleared its slot after the nodes were checked but
+            // before they were pushed back may have missed them, so they are
+            // checked again. Only the addresses are compared, as another
+            // thread may have released the nodes by now.
+            if kept.iter().all(|&node| self.published(node)) { return }
+        }
+    }
+
+    fn published(&self, node: *mut Node<T>) -> bool {
+        self.slots.iter().any(|slot| slot.node.load(atomic::SeqCst) == node)
+    }
+
+    // Pushes the list of nodes from `head` to `tail` onto the retired stack.
+    unsafe fn push_retired(&self, head: *mut Node<T>, tail: *mut Node<T>) {
+        loop {
+            let cur = self.retired.load(atomic::SeqCst);
+            (*tail).next = cur;
+            if self.retired.compare_and_swap(cur, head, atomic::SeqCst) == cur {
+                return
+            }
+        }
+    }
+}
+
+#[unsafe_destructor]
+impl<T> Drop for AtomicArc<T> {
+    fn drop(&mut self) {
+        unsafe {
+            let _current: Box<Node<T>> =
+                mem::transmute(self.current.load(atomic::SeqCst));
+            let mut head = self.retired.load(atomic::SeqCst);
+            while !head.is_null() {
+                let node: Box<Node<T>> = mem::transmute(head);
+                head = node.next;
+            }
+        }
+    }
+}
+
+fn node<T>(value: Arc<T>) -> *mut Node<T> {
+    let node = box Node { value: value, next: 0 as *mut Node<T> };
+    unsafe { mem::transmute(node) }
+}
+
+fn same<T>(a: &Arc<T>, b: &Arc<T>) -> bool {
+    &**a as *const T == &**b as *const T
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUint, INIT_ATOMIC_UINT, SeqCst};
+    use super::{AtomicArc, same};
+
+    #[test]
+    fn smoke() {
+        let a = AtomicArc::new(Arc::new(1i));
+        assert_eq!(*a.load(), 1);
+        a.store(Arc::new(2));
+        assert_eq!(*a.load(), 2);
+        assert_eq!(*a.swap(Arc::new(3)), 2);
+        assert_eq!(*a.load(), 3);
+    }
+
+    #[test]
+    fn compare_and_swap() {
+        let one = Arc::new(1i);
+        let a = AtomicArc::new(one.clone());
+        // equal values in different allocations don't count
+        let prev = a.compare_and_swap(&Arc::new(1), Arc::new(2));
+        assert!(same(&prev, &one));
+        assert_eq!(*a.load(), 1);
+        let prev = a.compare_and_swap(&one, Arc::new(2));
+        assert!(same(&prev, &one));
+        assert_eq!(*a.load(), 2);
+    }
+
+    #[test]
+    fn values_released() {
+        static DROPS: AtomicUint = INIT_ATOMIC_UINT;
+        struct D;
+        impl Drop for D {
+            fn drop(&mut self) { DROPS.fetch_add(1, SeqCst); }
+        }
+
+        let a = AtomicArc::new(Arc::new(D));
+        let held = a.load();
+        a.store(Arc::new(D));
+        a.store(Arc::new(D));
+        // nobody is reading, so the second value is gone already
+        assert_eq!(DROPS.load(SeqCst), 1);
+        drop(held);
+        assert_eq!(DROPS.load(SeqCst), 2);
+        drop(a);
+        assert_eq!(DROPS.load(SeqCst), 3);
+    }
+
+    #[test]
+    fn released_by_last_reader() {
+        static DROPS: AtomicUint = INIT_ATOMIC_UINT;
+        struct D;
+        impl Drop for D {
+            fn drop(&mut self) { DROPS.fetch_add(1, SeqCst); }
+        }
+
+        let a = AtomicArc::new(Arc::new(D));
+        // two readers in the middle of a `load` of the first value
+        let (s1, s2) = (a.claim(), a.claim());
+        a.protect(s1);
+        a.protect(s2);
+        a.store(Arc::new(D));
+        assert_eq!(DROPS.load(SeqCst), 0);
+        a.release(s1);
+        assert_eq!(DROPS.load(SeqCst), 0);
+        // the value goes as soon as its last reader is done, without waiting
+        // for another write
+        a.release(s2);
+        assert_eq!(DROPS.load(SeqCst), 1);
+        drop(a);
+        assert_eq!(DROPS.load(SeqCst), 2);
+    }
+
+    #[test]
+    fn concurrent() {
+        static N: uint = 8;
+        static M: uint = 1000;
+        let a = Arc::new(AtomicArc::new(Arc::new(0u)));
+        let (tx, rx) = channel();
+        for i in range(0, N) {
+            let a = a.clone();
+            let tx = tx.clone();
+            spawn(proc() {
+                for _ in range(0, M) {
+                    if i % 2 == 0 {
+                        // increment through compare_and_swap until it sticks
+                        loop {
+                            let cur = a.load();
+                            let prev = a.compare_and_swap(&cur,
+                                                          Arc::new(*cur + 1));
+                            if same(&prev, &cur) { break }
+                        }
+                    } else {
+                        assert!(*a.load() <= N / 2 * M);
+                    }
+                }
+                tx.send(());
+            });
+        }
+        drop(tx);
+        for _ in range(0, N) { rx.recv(); }
+        assert_eq!(*a.load(), N / 2 * M);
+    }
+}
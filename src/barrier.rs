@@ -1,4 +1,4 @@
-use {Mutex, Condvar};
+use {trace, valgrind, Mutex, Condvar};
 
 /// A barrier enables multiple tasks to synchronize the beginning
 /// of some computation.
@@ -52,21 +52,26 @@ impl Barrier {
     /// Barriers are re-usable after all tasks have rendezvoused once, and can
     /// be used continuously.
     pub fn wait(&self) {
+        trace::begin("Barrier::wait", self);
+        valgrind::happens_before(self);
         let mut lock = self.lock.lock();
         let local_gen = lock.generation_id;
         lock.count += 1;
         if lock.count < self.num_threads {
-            // We need a while loop to guard against spurious wakeups.
+            // The condition is re-checked to guard against spurious wakeups.
             // http://en.wikipedia.org/wiki/Spurious_wakeup
-            while local_gen == lock.generation_id &&
-                  lock.count < self.num_threads {
-                self.cvar.wait(&lock);
-            }
+            self.cvar.wait_while(&lock, || {
+                local_gen == lock.generation_id &&
+                    lock.count < self.num_threads
+            });
         } else {
             lock.count = 0;
             lock.generation_id += 1;
             self.cvar.notify_all();
         }
+        drop(lock);
+        valgrind::happens_after(self);
+        trace::end("Barrier::wait", self);
     }
 }
 
@@ -0,0 +1,392 @@
+//! Clocks consulted by timed operations
+//!
+//! All timed operations of this crate, such as `Condvar::wait_timeout`, measure
+//! time through the clock installed for the calling thread. By default this is
+//! the `SystemClock`, but a different clock can be installed with `set`.
+//!
+//! Points in time are represented by `Instant`, which can be used as an
+//! absolute deadline for operations such as `Condvar::wait_deadline`. A single
+//! deadline can be passed through any number of blocking operations without
+//! the drift which comes from repeatedly converting it to relative timeouts.
+//!
+//! The `MockClock` provided here only moves forward when it is explicitly
+//! advanced, allowing tests of code built on timed operations to have their
+//! timeouts fire deterministically without actually waiting for them.
+//!
+//! # Example
+//!
+//! ```
+//! use std::sync::Arc;
+//! use std::time::Duration;
+//! use sync::{Mutex, Condvar};
+//! use sync::clock::{mod, MockClock};
+//!
+//! let clock = MockClock::new();
+//! let clock2 = clock.clone();
+//! let pair = Arc::new((Mutex::new(()), Condvar::new()));
+//! let pair2 = pair.clone();
+//! let (tx, rx) = channel();
+//!
+//! spawn(proc() {
+//!     clock::set(clock2);
+//!     let &(ref lock, ref cvar) = &*pair2;
+//!     let guard = lock.lock();
+//!     tx.send(());
+//!     // this would otherwise block for an entire day
+//!     assert!(!cvar.wait_timeout(&guard, Duration::days(1)));
+//!     tx.send(());
+//! });
+//!
+//! // wait for the child to start waiting, and then fast forward
+//! rx.recv();
+//! let &(ref lock, _) = &*pair;
+//! drop(lock.lock());
+//! clock.advance(Duration::days(1));
+//! rx.recv();
+//! ```
+
+use std::cell::RefCell;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use {sys, Mutex, Condvar};
+
+/// A point in time, as measured by a `Clock`.
+///
+/// Instants are only meaningful relative to other instants taken from the same
+/// clock. They are measured from an arbitrary origin, which for the
+/// `SystemClock` is some point in the past which is unaffected by changes to
+/// the system time.
+#[deriving(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant { t: Duration }
+
+/// A source of time for timed operations.
+pub trait Clock {
+    /// Returns the current time according to this clock.
+    fn now(&self) -> Instant;
+
+    /// Blocks on `cvar`, atomically releasing `mutex`, until the condition
+    /// variable is notified or this clock reaches `deadline`.
+    ///
+    /// Returns `false` if the deadline has been reached, and `true` otherwise.
+    /// Like other waits on condition variables this may wake up spuriously.
+    /// The mutex is re-acquired when this function returns.
+    ///
+    /// This is unsafe for the same reasons as `sys::Condvar::wait_timeout`.
+    unsafe fn wait_until(&self, cvar: &sys::Condvar, mutex: &sys::Mutex,
+                         deadline: Instant) -> bool;
+}
+
+/// The monotonic clock of the operating system, used by default.
+pub struct SystemClock;
+
+/// A clock which only advances when told to.
+///
+/// Clones of a mock clock all share the same time, so a clone can be installed
+/// in one thread and advanced from another.
+///
+/// Threads in timed waits on a mock clock block until they are notified or
+/// the clock is advanced past their deadline, without waking up in between.
+#[deriving(Clone)]
+pub struct MockClock {
+    // The condvar is used to wait for `advance` to finish with a waiter.
+    state: Arc<(Mutex<MockState>, Condvar)>,
+}
+
+struct MockState {
+    now: Instant,
+    waiters: Vec<Waiter>,
+    next_id: uint,
+}
+
+// A thread blocked in `MockClock::wait_until`. The condvar and mutex are held
+// as addresses, they stay alive for as long as the waiter is registered.
+struct Waiter {
+    id: uint,
+    deadline: Instant,
+    cvar: uint,
+    mutex: uint,
+    // The number of calls to `advance` which are about to notify this waiter.
+    notifying: uint,
+}
+
+thread_local!(static CLOCK: RefCell<Option<Box<Clock + Send>>> = RefCell::new(None))
+
+/// Installs `clock` as the clock for all timed operations on the calling
+/// thread.
+pub fn set<C: Clock + Send>(clock: C) {
+    CLOCK.with(|c| *c.borrow_mut() = Some(box clock as Box<Clock + Send>));
+}
+
+/// Restores the `SystemClock` for the calling thread.
+pub fn reset() {
+    CLOCK.with(|c| *c.borrow_mut() = None);
+}
+
+/// Returns the current time according to the calling thread's clock.
+pub fn now() -> Instant {
+    with(|clock| clock.now())
+}
+
+/// Waits on `cvar` for at most `dur` according to the calling thread's clock.
+#[doc(hidden)]
+pub unsafe fn wait_timeout(cvar: &sys::Condvar, mutex: &sys::Mutex,
+                           dur: Duration) -> bool {
+    with(|clock| clock.wait_until(cvar, mutex, clock.now() + dur))
+}
+
+/// Waits on `cvar` until the calling thread's clock reaches `deadline`.
+#[doc(hidden)]
+pub unsafe fn wait_deadline(cvar: &sys::Condvar, mutex: &sys::Mutex,
+                            deadline: Instant) -> bool {
+    with(|clock| clock.wait_until(cvar, mutex, deadline))
+}
+
+/// Returns whether the calling thread is using the `SystemClock`.
+#[doc(hidden)]
+pub fn is_system() -> bool {
+    CLOCK.with(|c| c.borrow().is_none())
+}
+
+/// Repeatedly calls `f` until it returns `true` or the calling thread's clock
+/// reaches `deadline`, for timed operations which can't be built on a
+/// condition variable.
+///
+/// Returns whether `f` succeeded. `f` is always called at least once.
+#[doc(hidden)]
+pub fn poll_until(deadline: Instant, f: || -> bool) -> bool {
+    sys::time::poll(f, || now() >= deadline)
+}
+
+fn with<T>(f: |&Clock| -> T) -> T {
+    CLOCK.with(|c| {
+        match *c.borrow() {
+            Some(ref clock) => f(&**clock),
+            None => f(&SystemClock),
+        }
+    })
+}
+
+impl Instant {
+    /// Returns the current time according to the calling thread's clock.
+    pub fn now() -> Instant { now() }
+
+    /// Creates the instant which is `dur` after the origin of its clock.
+    ///
+    /// This is intended for implementations of `Clock`.
+    pub fn from_origin(dur: Duration) -> Instant { Instant { t: dur } }
+
+    /// Returns the amount of time since the origin of this instant's clock.
+    pub fn since_origin(&self) -> Duration { self.t }
+
+    /// Returns the amount of time elapsed from `earlier` to this instant, or
+    /// zero if `earlier` is later than this instant.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        if earlier.t > self.t { Duration::zero() } else { self.t - earlier.t }
+    }
+
+    /// Returns the amount of time elapsed since this instant according to the
+    /// calling thread's clock.
+    pub fn elapsed(&self) -> Duration { now().duration_since(*self) }
+}
+
+impl Add<Duration, Instant> for Instant {
+    fn add(&self, rhs: &Duration) -> Instant { Instant { t: self.t + *rhs } }
+}
+
+impl Sub<Duration, Instant> for Instant {
+    fn sub(&self, rhs: &Duration) -> Instant { Instant { t: self.t - *rhs } }
+}
+
+impl fmt::Show for Instant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Instant({})", self.t)
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant { t: unsafe { sys::time::monotonic() } }
+    }
+
+    unsafe fn wait_until(&self, cvar: &sys::Condvar, mutex: &sys::Mutex,
+                         deadline: Instant) -> bool {
+        cvar.wait_deadline(mutex, deadline.t)
+    }
+}
+
+impl MockClock {
+    /// Creates a new mock clock whose current time is zero.
+    pub fn new() -> MockClock {
+        let state = MockState {
+            now: Instant { t: Duration::zero() },
+            waiters: Vec::new(),
+            next_id: 0,
+        };
+        MockClock { state: Arc::new((Mutex::new(state), Condvar::new())) }
+    }
+
+    /// Moves this clock forward by `dur`.
+    ///
+    /// Any threads blocked in timed waits whose deadline has now passed are
+    /// woken up and time out. To wake them, this acquires the mutex each of
+    /// them is waiting with, so it must not be called while holding one of
+    /// those mutexes.
+    pub fn advance(&self, dur: Duration) {
+        let &(ref state, ref done) = &*self.state;
+        let expired: Vec<(uint, uint, uint)> = {
+            let mut s = state.lock();
+            let now = s.now + dur;
+            s.now = now;
+            s.waiters.iter_mut().filter(|w| w.deadline <= now).map(|w| {
+                w.notifying += 1;
+                (w.id, w.cvar, w.mutex)
+            }).collect()
+        };
+        if expired.len() == 0 { return }
+
+        // Holding the waiter's mutex while notifying means the notification
+        // can't be lost between the waiter registering and it blocking.
+        for &(_, cvar, mutex) in expired.iter() {
+            unsafe {
+                let mutex = &*(mutex as *const sys::Mutex);
+                let cvar = &*(cvar as *const sys::Condvar);
+                mutex.lock();
+                cvar.notify_all();
+                mutex.unlock();
+            }
+        }
+
+        let mut s = state.lock();
+        for w in s.waiters.iter_mut() {
+            if expired.iter().any(|&(id, _, _)| id == w.id) {
+                w.notifying -= 1;
+            }
+        }
+        done.notify_all();
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant { self.state.0.lock().now }
+
+    unsafe fn wait_until(&self, cvar: &sys::Condvar, mutex: &sys::Mutex,
+                         deadline: Instant) -> bool {
+        let &(ref state, ref done) = &*self.state;
+        let id = {
+            let mut s = state.lock();
+            if s.now >= deadline { return false }
+            let id = s.next_id;
+            s.next_id += 1;
+            s.waiters.push(Waiter {
+                id: id,
+                deadline: deadline,
+                cvar: cvar as *const sys::Condvar as uint,
+                mutex: mutex as *const sys::Mutex as uint,
+                notifying: 0,
+            });
+            id
+        };
+        cvar.wait(mutex);
+
+        // An `advance` may be about to notify this waiter, in which case the
+        // condvar must stay alive until it's done. It may also be blocked on
+        // the mutex, so that is released in the meantime.
+        let mut s = state.lock();
+        let mut released = false;
+        loop {
+            let i = s.waiters.iter().position(|w| w.id == id).unwrap();
+            if s.waiters[i].notifying == 0 {
+                s.waiters.remove(i);
+                break
+            }
+            if !released {
+                mutex.unlock();
+                released = true;
+            }
+            done.wait(&s);
+        }
+        let ret = s.now < deadline;
+        drop(s);
+        if released { mutex.lock() }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use {Mutex, Condvar};
+    use super::{MockClock, Instant};
+
+    #[test]
+    fn mock_now() {
+        let clock = MockClock::new();
+        let clock2 = clock.clone();
+        super::set(clock2);
+        let start = super::now();
+        assert_eq!(start, Instant::from_origin(Duration::zero()));
+        clock.advance(Duration::seconds(3));
+        assert_eq!(super::now(), start + Duration::seconds(3));
+        assert_eq!(start.elapsed(), Duration::seconds(3));
+        super::reset();
+    }
+
+    #[test]
+    fn instant_arithmetic() {
+        let a = Instant::from_origin(Duration::seconds(5));
+        let b = a + Duration::seconds(2);
+        assert!(b > a);
+        assert_eq!(b - Duration::seconds(2), a);
+        assert_eq!(b.duration_since(a), Duration::seconds(2));
+        assert_eq!(a.duration_since(b), Duration::zero());
+        assert_eq!(b.since_origin(), Duration::seconds(7));
+    }
+
+    #[test]
+    fn mock_timeout() {
+        let clock = MockClock::new();
+        let clock2 = clock.clone();
+        let pair = Arc::new((Mutex::new(()), Condvar::new()));
+        let pair2 = pair.clone();
+        let (tx, rx) = channel();
+
+        spawn(proc() {
+            super::set(clock2);
+            let &(ref lock, ref cvar) = &*pair2;
+            let g = lock.lock();
+            tx.send(None);
+            tx.send(Some(cvar.wait_timeout(&g, Duration::days(1))));
+        });
+
+        // once we can grab the lock the child is blocked in its wait
+        assert_eq!(rx.recv(), None);
+        let &(ref lock, _) = &*pair;
+        drop(lock.lock());
+        clock.advance(Duration::days(1));
+        assert_eq!(rx.recv(), Some(false));
+    }
+
+    #[test]
+    fn mock_notify() {
+        let clock = MockClock::new();
+        let pair = Arc::new((Mutex::new(false), Condvar::new()));
+        let pair2 = pair.clone();
+
+        super::set(clock);
+        spawn(proc() {
+            let &(ref lock, ref cvar) = &*pair2;
+            *lock.lock() = true;
+            cvar.notify_one();
+        });
+
+        let &(ref lock, ref cvar) = &*pair;
+        let g = lock.lock();
+        while !*g {
+            assert!(cvar.wait_timeout(&g, Duration::seconds(1)));
+        }
+        super::reset();
+    }
+}
@@ -1,7 +1,11 @@
-use std::sync::atomic::{mod, AtomicUint};
+use std::cell::UnsafeCell;
+use std::cmp;
+use std::task;
+use std::uint;
 use std::time::Duration;
 
-use {sys, mutex, StaticMutexGuard};
+use {sys, clock, mutex, faults, trace, Instant};
+use {Mutex, MutexGuard, StaticMutexGuard};
 
 /// A Condition Variable
 ///
@@ -14,10 +18,11 @@ use {sys, mutex, StaticMutexGuard};
 /// Functions in this module will block the current **thread** of execution and
 /// are bindings to system-provided condition variables where possible. Note
 /// that this module places one additional restriction over the system condition
-/// variables: each condvar can be used with precisely one mutex at runtime. Any
-/// attempt to use multiple mutexes on the same condition variable will result
-/// in a runtime panic. If this is not desired, then the unsafe primitives in
-/// `sys` do not have this restriction.
+/// variables: all threads waiting on a condvar at the same time must use the
+/// same mutex. Any attempt to wait with a different mutex while there are
+/// waiters using another will result in a runtime panic. Once all waiters have
+/// left, the condvar may be used with a different mutex. If this is not
+/// desired, then the unsafe primitives in `sys` do not have this restriction.
 ///
 /// # Example
 ///
@@ -59,15 +64,86 @@ pub struct Condvar { inner: Box<StaticCondvar> }
 /// ```
 pub struct StaticCondvar {
     inner: sys::Condvar,
-    mutex: AtomicUint,
+    lock: sys::Mutex,
+    binding: UnsafeCell<Binding>,
+}
+
+// The mutex that the waiters of a condvar are currently using, protected by
+// the condvar's internal lock. The mutex is only meaningful while there are
+// waiters. Of those waiters, `signalled` notifications are outstanding, each
+// of which is consumed by the next waiter to leave its wait.
+struct Binding {
+    mutex: uint,
+    waiters: uint,
+    signalled: uint,
 }
 
 /// Constant initializer for a statically allocated condition variable.
 pub const CONDVAR_INIT: StaticCondvar = StaticCondvar {
     inner: sys::CONDVAR_INIT,
-    mutex: atomic::INIT_ATOMIC_UINT,
+    lock: sys::MUTEX_INIT,
+    binding: UnsafeCell { value: Binding { mutex: 0, waiters: 0, signalled: 0 } },
 };
 
+/// A mutex together with a condition variable which can only be used with
+/// that mutex.
+///
+/// These are created with `Mutex::with_condvar`, and own the mutex they
+/// contain. Waiting is done through the guards returned by
+/// `MutexCondvar::lock`, which lock that mutex, so a `MutexCondvar` can never
+/// be waited on with any other mutex and none of the dynamic checking that
+/// `Condvar` performs is needed.
+///
+/// # Example
+///
+/// ```
+/// use std::sync::Arc;
+/// use sync::Mutex;
+///
+/// let cvar = Arc::new(Mutex::with_condvar(false));
+/// let cvar2 = cvar.clone();
+///
+/// spawn(proc() {
+///     *cvar2.lock() = true;
+///     cvar2.notify_one();
+/// });
+///
+/// // wait for the thread to start up
+/// let started = cvar.lock();
+/// while !*started {
+///     started.wait();
+/// }
+/// ```
+pub struct MutexCondvar<T> {
+    mutex: Mutex<T>,
+    inner: Box<StaticCondvar>,
+}
+
+/// An RAII guard of the mutex of a `MutexCondvar`, through which the
+/// condition variable is waited on.
+///
+/// The mutex is unlocked when this guard is dropped. The data protected by
+/// the mutex can be accessed through this guard via its `Deref` and
+/// `DerefMut` implementations.
+#[must_use]
+pub struct MutexCondvarGuard<'a, T: 'a> {
+    // funny underscores due to how Deref/DerefMut currently work (they
+    // disregard field privacy).
+    __cvar: &'a MutexCondvar<T>,
+    __guard: MutexGuard<'a, T>,
+}
+
+/// The result of a timed wait which consumed its guard, such as
+/// `Condvar::wait_timeout_owned`.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct WaitTimeoutResult(bool);
+
+impl WaitTimeoutResult {
+    /// Returns whether the wait returned because its timeout elapsed, rather
+    /// than because of a notification (or a spurious wakeup).
+    pub fn timed_out(&self) -> bool { self.0 }
+}
+
 /// A trait for vaules which can be passed to the waiting methods of condition
 /// variables. This is implemented by the mutex guards in this module.
 ///
@@ -82,12 +158,15 @@ impl Condvar {
     /// Creates a new condition variable which is ready to be waited on and
     /// notified.
     pub fn new() -> Condvar {
-        Condvar {
-            inner: box StaticCondvar {
-                inner: unsafe { sys::Condvar::new() },
-                mutex: AtomicUint::new(0),
-            }
-        }
+        let mut inner = box StaticCondvar {
+            inner: unsafe { sys::Condvar::new() },
+            lock: unsafe { sys::Mutex::new() },
+            binding: UnsafeCell::new(Binding { mutex: 0, waiters: 0, signalled: 0 }),
+        };
+        // Now that the condvar has reached its final address it can be told
+        // to measure timeouts against the monotonic clock.
+        unsafe { inner.inner.init() }
+        Condvar { inner: inner }
     }
 
     /// Block the current thread until this condition variable receives a
@@ -106,10 +185,11 @@ impl Condvar {
     ///
     /// # Panics
     ///
-    /// This function will `panic!()` if it is used with more than one mutex
-    /// over time. Each condition variable is dynamically bound to exactly one
-    /// mutex to ensure defined behavior across platforms. If this functionality
-    /// is not desired, then unsafe primitives in `sys` are provided.
+    /// This function will `panic!()` if other threads are concurrently waiting
+    /// on this condition variable with a different mutex. Each condition
+    /// variable is dynamically bound to the mutex of its current waiters to
+    /// ensure defined behavior across platforms. If this functionality is not
+    /// desired, then unsafe primitives in `sys` are provided.
     pub fn wait<T: AsMutexGuard>(&self, mutex_guard: &T) {
         unsafe {
             let me: &'static Condvar = &*(self as *const _);
@@ -127,6 +207,9 @@ impl Condvar {
     ///
     /// Like `wait`, the lock specified will be re-acquired when this function
     /// returns, regardless of whether the timeout elapsed or not.
+    ///
+    /// The timeout is measured by the calling thread's clock, see the `clock`
+    /// module.
     pub fn wait_timeout<T: AsMutexGuard>(&self, mutex_guard: &T,
                                          dur: Duration) -> bool {
         unsafe {
@@ -135,14 +218,156 @@ impl Condvar {
         }
     }
 
+    /// Wait on this condition variable for a notification, timing out once a
+    /// specified point in time has been reached.
+    ///
+    /// The semantics of this function are equivalent to `wait_timeout()`
+    /// except that the thread will be blocked until the calling thread's clock
+    /// reaches `deadline` rather than for a relative duration. If the deadline
+    /// has already passed then this returns `false` without blocking.
+    ///
+    /// Passing the same deadline to a series of waits avoids the drift which
+    /// accumulates from converting it to a relative timeout for each one.
+    pub fn wait_deadline<T: AsMutexGuard>(&self, mutex_guard: &T,
+                                          deadline: Instant) -> bool {
+        unsafe {
+            let me: &'static Condvar = &*(self as *const _);
+            me.inner.wait_deadline(mutex_guard, deadline)
+        }
+    }
+
+    /// Block the current thread until this condition variable receives a
+    /// notification, taking ownership of the guard while waiting.
+    ///
+    /// This is the same as `wait` except that the guard is moved into this
+    /// function and returned once the lock has been re-acquired, so no
+    /// references derived from the guard can be held across the wait.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic in the same situations as `wait`.
+    pub fn wait_owned<T: AsMutexGuard>(&self, mutex_guard: T) -> T {
+        self.wait(&mutex_guard);
+        mutex_guard
+    }
+
+    /// Wait on this condition variable for a notification, timing out after a
+    /// specified duration, taking ownership of the guard while waiting.
+    ///
+    /// This is the same as `wait_timeout` except that the guard is moved into
+    /// this function and returned along with a `WaitTimeoutResult` describing
+    /// whether the wait timed out.
+    pub fn wait_timeout_owned<T: AsMutexGuard>(&self, mutex_guard: T,
+                                               dur: Duration)
+                                               -> (T, WaitTimeoutResult) {
+        let notified = self.wait_timeout(&mutex_guard, dur);
+        (mutex_guard, WaitTimeoutResult(!notified))
+    }
+
+    /// Wait on this condition variable for a notification, timing out once a
+    /// specified point in time has been reached, taking ownership of the guard
+    /// while waiting.
+    ///
+    /// See `wait_timeout_owned` and `wait_deadline`.
+    pub fn wait_deadline_owned<T: AsMutexGuard>(&self, mutex_guard: T,
+                                                deadline: Instant)
+                                                -> (T, WaitTimeoutResult) {
+        let notified = self.wait_deadline(&mutex_guard, deadline);
+        (mutex_guard, WaitTimeoutResult(!notified))
+    }
+
+    /// Block the current thread until `condition` returns `true`.
+    ///
+    /// The condition is checked before blocking and then again each time this
+    /// thread is woken up, so spurious wakeups are handled by this function.
+    /// The condition is always called with the lock specified held, and the
+    /// lock is held when this function returns.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic in the same situations as `wait`.
+    pub fn wait_until<T: AsMutexGuard>(&self, mutex_guard: &T,
+                                       condition: || -> bool) {
+        unsafe {
+            let me: &'static Condvar = &*(self as *const _);
+            me.inner.wait_until(mutex_guard, condition)
+        }
+    }
+
+    /// Block the current thread while `condition` returns `true`.
+    ///
+    /// This is the same as `wait_until` with the condition negated.
+    pub fn wait_while<T: AsMutexGuard>(&self, mutex_guard: &T,
+                                       condition: || -> bool) {
+        unsafe {
+            let me: &'static Condvar = &*(self as *const _);
+            me.inner.wait_while(mutex_guard, condition)
+        }
+    }
+
+    /// Block the current thread until `condition` returns `true`, giving up
+    /// after a specified duration.
+    ///
+    /// Like `wait_until`, the condition is re-checked each time this thread is
+    /// woken up. The duration `dur` is an overall budget for the whole call,
+    /// so the time spent before a spurious wakeup is deducted from subsequent
+    /// waits.
+    ///
+    /// Returns whether the condition was satisfied. If the duration elapses
+    /// then the condition is checked one last time before `false` is returned.
+    pub fn wait_timeout_until<T: AsMutexGuard>(&self, mutex_guard: &T,
+                                               dur: Duration,
+                                               condition: || -> bool) -> bool {
+        unsafe {
+            let me: &'static Condvar = &*(self as *const _);
+            me.inner.wait_timeout_until(mutex_guard, dur, condition)
+        }
+    }
+
+    /// Block the current thread while `condition` returns `true`, giving up
+    /// after a specified duration.
+    ///
+    /// This is the same as `wait_timeout_until` with the condition negated.
+    /// Returns `true` if the condition stopped holding, or `false` if the
+    /// duration elapsed while it still held.
+    pub fn wait_timeout_while<T: AsMutexGuard>(&self, mutex_guard: &T,
+                                               dur: Duration,
+                                               condition: || -> bool) -> bool {
+        unsafe {
+            let me: &'static Condvar = &*(self as *const _);
+            me.inner.wait_timeout_while(mutex_guard, dur, condition)
+        }
+    }
+
     /// Wake up one blocked thread on this condvar.
     ///
     /// If there is a blocked thread on this condition variable, then it will
     /// be woken up from its call to `wait` or `wait_timeout`. Calls to
     /// `notify_one` are not buffered in any way.
     ///
-    /// To wake up all threads, see `notify_one()`.
-    pub fn notify_one(&self) { unsafe { self.inner.inner.notify_one() } }
+    /// To wake up all threads, see `notify_all()`.
+    pub fn notify_one(&self) {
+        unsafe {
+            let me: &'static Condvar = &*(self as *const _);
+            me.inner.notify_one()
+        }
+    }
+
+    /// Wake up at most `n` blocked threads on this condvar.
+    ///
+    /// Returns the number of threads woken up, which are those blocked on this
+    /// condition variable that had not already been woken up by an earlier
+    /// notification. Each of them returns from its wait as having been
+    /// notified, even if its timeout elapsed at the same time. The count is
+    /// exact if the mutex used with this condition variable is held while
+    /// notifying, otherwise threads which are about to block may be counted
+    /// without noticing the notification.
+    pub fn notify_n(&self, n: uint) -> uint {
+        unsafe {
+            let me: &'static Condvar = &*(self as *const _);
+            me.inner.notify_n(n)
+        }
+    }
 
     /// Wake up all blocked threads on this condvar.
     ///
@@ -150,15 +375,133 @@ impl Condvar {
     /// variable are awoken. Calls to `notify_all()` are not buffered in any way.
     ///
     /// To wake up only one thread, see `notify_one()`.
-    pub fn notify_all(&self) { unsafe { self.inner.inner.notify_all() } }
+    pub fn notify_all(&self) {
+        unsafe {
+            let me: &'static Condvar = &*(self as *const _);
+            me.inner.notify_all()
+        }
+    }
 }
 
 impl Drop for Condvar {
     fn drop(&mut self) {
-        unsafe { self.inner.inner.destroy() }
+        unsafe {
+            self.inner.inner.destroy();
+            self.inner.lock.destroy();
+        }
     }
 }
 
+/// Creates a condition variable paired with `mutex`.
+pub fn mutex_condvar<T: Send>(mutex: Mutex<T>) -> MutexCondvar<T> {
+    let mut inner = box StaticCondvar {
+        inner: unsafe { sys::Condvar::new() },
+        lock: unsafe { sys::Mutex::new() },
+        binding: UnsafeCell::new(Binding { mutex: 0, waiters: 0, signalled: 0 }),
+    };
+    unsafe { inner.inner.init() }
+    MutexCondvar { mutex: mutex, inner: inner }
+}
+
+impl<T: Send> MutexCondvar<T> {
+    /// Acquires the mutex of this condition variable, blocking the current
+    /// thread until it is able to do so.
+    ///
+    /// See `Mutex::lock`.
+    pub fn lock(&self) -> MutexCondvarGuard<T> {
+        MutexCondvarGuard { __cvar: self, __guard: self.mutex.lock() }
+    }
+
+    /// Attempts to acquire the mutex of this condition variable.
+    ///
+    /// See `Mutex::try_lock`.
+    pub fn try_lock(&self) -> Option<MutexCondvarGuard<T>> {
+        self.mutex.try_lock().map(|guard| {
+            MutexCondvarGuard { __cvar: self, __guard: guard }
+        })
+    }
+
+    /// Wake up one blocked thread on this condvar.
+    ///
+    /// See `Condvar::notify_one`.
+    pub fn notify_one(&self) {
+        self.inner.notify("Condvar::notify_one", 1);
+    }
+
+    /// Wake up at most `n` blocked threads on this condvar.
+    ///
+    /// See `Condvar::notify_n`.
+    pub fn notify_n(&self, n: uint) -> uint {
+        self.inner.notify("Condvar::notify_n", n)
+    }
+
+    /// Wake up all blocked threads on this condvar.
+    ///
+    /// See `Condvar::notify_all`.
+    pub fn notify_all(&self) {
+        self.inner.notify("Condvar::notify_all", uint::MAX);
+    }
+}
+
+impl<T: Send> Drop for MutexCondvar<T> {
+    fn drop(&mut self) {
+        unsafe {
+            self.inner.inner.destroy();
+            self.inner.lock.destroy();
+        }
+    }
+}
+
+impl<'a, T: Send> MutexCondvarGuard<'a, T> {
+    /// Block the current thread until the condition variable of this guard
+    /// receives a notification, unlocking the mutex while blocked.
+    ///
+    /// See `Condvar::wait`.
+    pub fn wait(&self) {
+        let cvar = &*self.__cvar.inner;
+        unsafe {
+            let lock = self.__guard.as_mutex_guard();
+            cvar.wait_with(lock, false, "Condvar::wait", |sys| {
+                if faults::spurious_wakeup() {
+                    yield_lock(sys);
+                } else {
+                    cvar.inner.wait(sys);
+                }
+                true
+            });
+        }
+    }
+
+    /// Wait on the condition variable of this guard for a notification,
+    /// timing out after a specified duration.
+    ///
+    /// See `Condvar::wait_timeout`.
+    pub fn wait_timeout(&self, dur: Duration) -> bool {
+        unsafe {
+            let lock = self.__guard.as_mutex_guard();
+            self.__cvar.inner.wait_timeout_with(lock, false, dur)
+        }
+    }
+
+    /// Wait on the condition variable of this guard for a notification,
+    /// timing out once a specified point in time has been reached.
+    ///
+    /// See `Condvar::wait_deadline`.
+    pub fn wait_deadline(&self, deadline: Instant) -> bool {
+        unsafe {
+            let lock = self.__guard.as_mutex_guard();
+            self.__cvar.inner.wait_deadline_with(lock, false, deadline)
+        }
+    }
+}
+
+impl<'a, T> Deref<T> for MutexCondvarGuard<'a, T> {
+    fn deref(&self) -> &T { &*self.__guard }
+}
+impl<'a, T> DerefMut<T> for MutexCondvarGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T { &mut *self.__guard }
+}
+
 impl StaticCondvar {
     /// Block the current thread until this condition variable receives a
     /// notification.
@@ -167,10 +510,14 @@ impl StaticCondvar {
     pub fn wait<T: AsMutexGuard>(&'static self, mutex_guard: &T) {
         unsafe {
             let lock = mutex_guard.as_mutex_guard();
-            let sys = mutex::guard_lock(lock);
-            self.verify(sys);
-            self.inner.wait(sys);
-            (*mutex::guard_poison(lock)).check("mutex");
+            self.wait_with(lock, true, "Condvar::wait", |sys| {
+                if faults::spurious_wakeup() {
+                    yield_lock(sys);
+                } else {
+                    self.inner.wait(sys);
+                }
+                true
+            });
         }
     }
 
@@ -182,23 +529,120 @@ impl StaticCondvar {
                                          dur: Duration) -> bool {
         unsafe {
             let lock = mutex_guard.as_mutex_guard();
-            let sys = mutex::guard_lock(lock);
-            self.verify(sys);
-            let ret = self.inner.wait_timeout(sys, dur);
-            (*mutex::guard_poison(lock)).check("mutex");
-            return ret;
+            self.wait_timeout_with(lock, true, dur)
+        }
+    }
+
+    /// Wait on this condition variable for a notification, timing out once a
+    /// specified point in time has been reached.
+    ///
+    /// See `Condvar::wait_deadline`.
+    pub fn wait_deadline<T: AsMutexGuard>(&self, mutex_guard: &T,
+                                          deadline: Instant) -> bool {
+        unsafe {
+            let lock = mutex_guard.as_mutex_guard();
+            self.wait_deadline_with(lock, true, deadline)
         }
     }
 
+    /// Block the current thread until this condition variable receives a
+    /// notification, taking ownership of the guard while waiting.
+    ///
+    /// See `Condvar::wait_owned`.
+    pub fn wait_owned<T: AsMutexGuard>(&'static self, mutex_guard: T) -> T {
+        self.wait(&mutex_guard);
+        mutex_guard
+    }
+
+    /// Wait on this condition variable for a notification, timing out after a
+    /// specified duration, taking ownership of the guard while waiting.
+    ///
+    /// See `Condvar::wait_timeout_owned`.
+    pub fn wait_timeout_owned<T: AsMutexGuard>(&self, mutex_guard: T,
+                                               dur: Duration)
+                                               -> (T, WaitTimeoutResult) {
+        let notified = self.wait_timeout(&mutex_guard, dur);
+        (mutex_guard, WaitTimeoutResult(!notified))
+    }
+
+    /// Wait on this condition variable for a notification, timing out once a
+    /// specified point in time has been reached, taking ownership of the guard
+    /// while waiting.
+    ///
+    /// See `Condvar::wait_deadline_owned`.
+    pub fn wait_deadline_owned<T: AsMutexGuard>(&self, mutex_guard: T,
+                                                deadline: Instant)
+                                                -> (T, WaitTimeoutResult) {
+        let notified = self.wait_deadline(&mutex_guard, deadline);
+        (mutex_guard, WaitTimeoutResult(!notified))
+    }
+
+    /// Block the current thread until `condition` returns `true`.
+    ///
+    /// See `Condvar::wait_until`.
+    pub fn wait_until<T: AsMutexGuard>(&'static self, mutex_guard: &T,
+                                       mut condition: || -> bool) {
+        while !condition() {
+            self.wait(mutex_guard);
+        }
+    }
+
+    /// Block the current thread while `condition` returns `true`.
+    ///
+    /// See `Condvar::wait_while`.
+    pub fn wait_while<T: AsMutexGuard>(&'static self, mutex_guard: &T,
+                                       mut condition: || -> bool) {
+        self.wait_until(mutex_guard, || !condition())
+    }
+
+    /// Block the current thread until `condition` returns `true`, giving up
+    /// after a specified duration.
+    ///
+    /// See `Condvar::wait_timeout_until`.
+    pub fn wait_timeout_until<T: AsMutexGuard>(&self, mutex_guard: &T,
+                                               dur: Duration,
+                                               mut condition: || -> bool)
+                                               -> bool {
+        let deadline = clock::now() + dur;
+        while !condition() {
+            if !self.wait_deadline(mutex_guard, deadline) {
+                return condition()
+            }
+        }
+        true
+    }
+
+    /// Block the current thread while `condition` returns `true`, giving up
+    /// after a specified duration.
+    ///
+    /// See `Condvar::wait_timeout_while`.
+    pub fn wait_timeout_while<T: AsMutexGuard>(&self, mutex_guard: &T,
+                                               dur: Duration,
+                                               mut condition: || -> bool)
+                                               -> bool {
+        self.wait_timeout_until(mutex_guard, dur, || !condition())
+    }
+
     /// Wake up one blocked thread on this condvar.
     ///
     /// See `Condvar::notify_one`.
-    pub fn notify_one(&'static self) { unsafe { self.inner.notify_one() } }
+    pub fn notify_one(&'static self) {
+        self.notify("Condvar::notify_one", 1);
+    }
+
+    /// Wake up at most `n` blocked threads on this condvar.
+    ///
+    /// See `Condvar::notify_n`.
+    pub fn notify_n(&'static self, n: uint) -> uint {
+        self.notify("Condvar::notify_n", n)
+    }
 
     /// Wake up all blocked threads on this condvar.
     ///
     /// See `Condvar::notify_all`.
-    pub fn notify_all(&'static self) { unsafe { self.inner.notify_all() } }
+    pub fn notify_all(&'static self) {
+        self.notify("Condvar::notify_all", uint::MAX);
+    }
 
     /// Deallocate all resources associated with this static condvar.
     ///
@@ -207,26 +651,143 @@ impl StaticCondvar {
     /// users of the condvar. This method is required to be called to not leak
     /// memory on all platforms.
     pub unsafe fn destroy(&'static self) {
-        self.inner.destroy()
+        self.inner.destroy();
+        self.lock.destroy();
     }
 
-    fn verify(&self, mutex: &sys::Mutex) {
+    // Registers a waiter using `mutex`, binding this condvar to it if there
+    // are no other waiters. Each call must be paired with `unbind` once the
+    // wait has finished. Unless `check` is false, this panics if other
+    // waiters are using a different mutex.
+    unsafe fn bind(&self, mutex: &sys::Mutex, check: bool) {
         let addr = mutex as *const _ as uint;
-        if self.mutex.load(atomic::SeqCst) != addr {
-            match self.mutex.compare_and_swap(0, addr, atomic::SeqCst) {
-                0 => {}
-                _ => panic!("attempted to use a condition variable with two \
-                             mutexes"),
+        self.lock.lock();
+        let binding = &mut *self.binding.get();
+        let ok = !check || binding.waiters == 0 || binding.mutex == addr;
+        if ok {
+            binding.mutex = addr;
+            binding.waiters += 1;
+        }
+        self.lock.unlock();
+        if !ok {
+            panic!("attempted to use a condition variable with two mutexes");
+        }
+    }
+
+    // Unregisters a waiter, returning whether it should report that it was
+    // notified given whether its wait returned before timing out. A waiter
+    // which leaves while there are outstanding notifications consumes one of
+    // them and reports being notified even if it timed out, so every waiter
+    // counted by `notify` sees a notification.
+    unsafe fn unbind(&self, notified: bool) -> bool {
+        self.lock.lock();
+        let binding = &mut *self.binding.get();
+        binding.waiters -= 1;
+        let ret = if binding.signalled > 0 {
+            binding.signalled -= 1;
+            true
+        } else {
+            notified
+        };
+        self.lock.unlock();
+        ret
+    }
+
+    // Wakes up at most `n` waiters, returning the number of waiters which had
+    // not already been notified that were woken up.
+    fn notify(&self, name: &'static str, n: uint) -> uint {
+        trace::instant(name, &self.inner);
+        unsafe {
+            self.lock.lock();
+            let binding = &mut *self.binding.get();
+            let woken = cmp::min(n, binding.waiters - binding.signalled);
+            binding.signalled += woken;
+            // Waiters register themselves while holding their mutex, before
+            // blocking, so if there are none registered there's nobody to
+            // wake up. Otherwise signal as many waiters as asked, including
+            // any already notified in case those notifications were lost.
+            if n >= binding.waiters {
+                if binding.waiters > 0 {
+                    self.inner.notify_all();
+                }
+            } else {
+                for _ in range(0, n) {
+                    self.inner.notify_one();
+                }
             }
+            self.lock.unlock();
+            woken
         }
     }
+
+    unsafe fn wait_timeout_with(&self, lock: &StaticMutexGuard, check: bool,
+                                dur: Duration) -> bool {
+        self.wait_with(lock, check, "Condvar::wait_timeout", |sys| {
+            if faults::spurious_wakeup() {
+                yield_lock(sys);
+                true
+            } else if faults::timeout() {
+                yield_lock(sys);
+                false
+            } else {
+                clock::wait_timeout(&self.inner, sys, dur)
+            }
+        })
+    }
+
+    unsafe fn wait_deadline_with(&self, lock: &StaticMutexGuard, check: bool,
+                                 deadline: Instant) -> bool {
+        self.wait_with(lock, check, "Condvar::wait_deadline", |sys| {
+            if faults::spurious_wakeup() {
+                yield_lock(sys);
+                true
+            } else if faults::timeout() {
+                yield_lock(sys);
+                false
+            } else {
+                clock::wait_deadline(&self.inner, sys, deadline)
+            }
+        })
+    }
+
+    // Releases the mutex of `lock` while `f` waits on it, re-checking the
+    // mutex for poison on wakeup. See `bind` for the meaning of `check`.
+    unsafe fn wait_with(&self, lock: &StaticMutexGuard, check: bool,
+                        name: &'static str,
+                        f: |&sys::Mutex| -> bool) -> bool {
+        let sys = mutex::guard_lock(lock);
+        self.bind(sys, check);
+        trace::release("Mutex", sys);
+        trace::begin(name, &self.inner);
+        let ret = f(sys);
+        trace::end(name, &self.inner);
+        trace::acquire("Mutex", sys);
+        let ret = self.unbind(ret);
+        (*mutex::guard_poison(lock)).check("mutex");
+        ret
+    }
+}
+
+// Stands in for a wait which returns without being notified, such as an
+// injected spurious wakeup or timeout. Just like a real wakeup this releases
+// `lock` and lets other threads run before re-acquiring it, otherwise a
+// waiter looping on its predicate would spin without ever letting another
+// thread change it.
+pub unsafe fn yield_lock(lock: &sys::Mutex) {
+    lock.unlock();
+    task::deschedule();
+    lock.lock();
 }
 
 #[cfg(test)]
 mod tests {
+    use std::sync::Arc;
+    use std::task;
     use std::time::Duration;
+    use std::uint;
     use super::{Condvar, StaticCondvar, CONDVAR_INIT};
-    use mutex::{StaticMutex, MUTEX_INIT};
+    use mutex::{Mutex, StaticMutex, MUTEX_INIT};
+    use Instant;
 
     #[test]
     fn smoke() {
@@ -290,22 +851,198 @@ mod tests {
     }
 
     #[test]
-    #[should_fail]
+    fn wait_timeout_huge() {
+        let pair = Arc::new((Mutex::new(()), Condvar::new()));
+        let pair2 = pair.clone();
+
+        let &(ref lock, ref cvar) = &*pair;
+        let g = lock.lock();
+        spawn(proc() {
+            let &(ref lock, ref cvar) = &*pair2;
+            let _g = lock.lock();
+            cvar.notify_one();
+        });
+        assert!(cvar.wait_timeout(&g, Duration::max_value()));
+    }
+
+    #[test]
+    fn wait_deadline() {
+        let m = Mutex::new(());
+        let c = Condvar::new();
+
+        let g = m.lock();
+        let deadline = Instant::now() + Duration::milliseconds(10);
+        while c.wait_deadline(&g, deadline) {}
+        assert!(Instant::now() >= deadline);
+        assert!(!c.wait_deadline(&g, deadline));
+    }
+
+    #[test]
+    fn static_wait_deadline() {
+        static C: StaticCondvar = CONDVAR_INIT;
+        static M: StaticMutex = MUTEX_INIT;
+
+        let g = M.lock();
+        let deadline = Instant::now() + Duration::milliseconds(10);
+        while C.wait_deadline(&g, deadline) {}
+        assert!(Instant::now() >= deadline);
+        drop(g);
+        unsafe { C.destroy(); M.destroy(); }
+    }
+
+    #[test]
+    fn wait_owned() {
+        let pair = Arc::new((Mutex::new(false), Condvar::new()));
+        let pair2 = pair.clone();
+        spawn(proc() {
+            let &(ref lock, ref cvar) = &*pair2;
+            *lock.lock() = true;
+            cvar.notify_one();
+        });
+
+        let &(ref lock, ref cvar) = &*pair;
+        let mut g = lock.lock();
+        while !*g {
+            g = cvar.wait_owned(g);
+        }
+    }
+
+    #[test]
+    fn wait_timeout_owned() {
+        let m = Mutex::new(());
+        let c = Condvar::new();
+        let (g, res) = c.wait_timeout_owned(m.lock(), Duration::milliseconds(1));
+        assert!(res.timed_out());
+        drop(g);
+    }
+
+    #[test]
+    fn notify_n() {
+        let pair = Arc::new((Mutex::new(0u), Condvar::new()));
+        let (tx, rx) = channel();
+        for _ in range(0u, 3) {
+            let pair2 = pair.clone();
+            let tx = tx.clone();
+            spawn(proc() {
+                let &(ref lock, ref cvar) = &*pair2;
+                let mut g = lock.lock();
+                *g += 1;
+                while *g <= 3 {
+                    cvar.wait(&g);
+                }
+                tx.send(());
+            });
+        }
+
+        // once all three threads have registered, they're all blocked
+        let &(ref lock, ref cvar) = &*pair;
+        loop {
+            let mut g = lock.lock();
+            if *g == 3 {
+                *g += 1;
+                assert_eq!(cvar.notify_n(2), 2);
+                assert_eq!(cvar.notify_n(uint::MAX), 1);
+                assert_eq!(cvar.notify_n(1), 0);
+                break
+            }
+        }
+        for _ in range(0u, 3) { rx.recv(); }
+    }
+
+    #[test]
+    fn wait_until() {
+        static C: StaticCondvar = CONDVAR_INIT;
+        static M: StaticMutex = MUTEX_INIT;
+        static mut READY: bool = false;
+
+        let g = M.lock();
+        spawn(proc() {
+            let _g = M.lock();
+            unsafe { READY = true; }
+            C.notify_one();
+        });
+        C.wait_until(&g, || unsafe { READY });
+        assert!(unsafe { READY });
+        drop(g);
+        unsafe { C.destroy(); M.destroy(); }
+    }
+
+    #[test]
+    fn wait_timeout_until() {
+        let m = Mutex::new(0u);
+        let c = Condvar::new();
+
+        let g = m.lock();
+        assert!(!c.wait_timeout_until(&g, Duration::milliseconds(10),
+                                      || *g > 0));
+        assert!(c.wait_timeout_while(&g, Duration::days(1), || *g > 0));
+    }
+
+    #[test]
     fn two_mutexes() {
         static M1: StaticMutex = MUTEX_INIT;
         static M2: StaticMutex = MUTEX_INIT;
         static C: StaticCondvar = CONDVAR_INIT;
+        static mut DONE: bool = false;
 
         let g = M1.lock();
         spawn(proc() {
+            // once M1 is acquired the main thread is waiting with it
             let _g = M1.lock();
+            let r = task::try(proc() {
+                C.wait(&M2.lock());
+            });
+            assert!(r.is_err());
+            unsafe { DONE = true; }
             C.notify_one();
         });
-        C.wait(&g);
+        while !unsafe { DONE } {
+            C.wait(&g);
+        }
+        drop(g);
+        unsafe { C.destroy(); M1.destroy(); M2.destroy(); }
+    }
+
+    #[test]
+    fn mutex_condvar() {
+        let cvar = Arc::new(Mutex::with_condvar(false));
+        let cvar2 = cvar.clone();
+        spawn(proc() {
+            *cvar2.lock() = true;
+            cvar2.notify_one();
+        });
+
+        let g = cvar.lock();
+        while !*g {
+            g.wait();
+        }
+    }
+
+    #[test]
+    fn mutex_condvar_timeout() {
+        let c = Mutex::with_condvar(0i);
+        let mut g = c.lock();
+        *g += 1;
+        assert!(!g.wait_timeout(Duration::milliseconds(1)));
+        assert!(!g.wait_deadline(Instant::now()));
         drop(g);
+        assert_eq!(*c.try_lock().unwrap(), 1);
+    }
 
-        C.wait(&M2.lock());
+    #[test]
+    fn rebind() {
+        static M1: StaticMutex = MUTEX_INIT;
+        static M2: StaticMutex = MUTEX_INIT;
+        static C: StaticCondvar = CONDVAR_INIT;
 
+        let g = M1.lock();
+        assert!(!C.wait_timeout(&g, Duration::milliseconds(1)));
+        drop(g);
+
+        let g = M2.lock();
+        assert!(!C.wait_timeout(&g, Duration::milliseconds(1)));
+        drop(g);
+        unsafe { C.destroy(); M1.destroy(); M2.destroy(); }
     }
 }
 
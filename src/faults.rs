@@ -0,0 +1,185 @@
+//! Fault injection for testing
+//!
+//! When this crate is compiled with the `faults` feature, the safe primitives
+//! will occasionally exhibit behavior which is permitted by their contracts
+//! but rarely happens in practice:
+//!
+//! * `Condvar::wait` and `Condvar::wait_timeout` return early as if they had
+//!   woken up spuriously.
+//! * `Condvar::wait_timeout` reports that it timed out without waiting.
+//!
+//!   Like a real wakeup, these still release the mutex and let other threads
+//!   run before re-acquiring it.
+//! * `try_lock`, `try_read`, and `try_write` fail to acquire their lock.
+//!
+//! This can be used to flush out code which, for example, forgets to re-check
+//! its predicate in a loop around `Condvar::wait`.
+//!
+//! Faults are configured per-thread with `enable`, and each thread picks when
+//! to inject a fault from its own random number generator. Re-enabling faults
+//! with the same seed will produce the same sequence of faults for the same
+//! sequence of operations on that thread.
+//!
+//! # Example
+//!
+//! ```
+//! use sync::{faults, Mutex};
+//!
+//! // inject a fault, on average, once every 4 operations
+//! faults::enable(42, 4);
+//!
+//! let m = Mutex::new(());
+//! let mut acquired = 0u;
+//! for _ in range(0u, 100) {
+//!     if m.try_lock().is_some() { acquired += 1; }
+//! }
+//! assert!(acquired < 100);
+//!
+//! faults::disable();
+//! ```
+
+use std::cell::RefCell;
+use std::rand::{Rng, SeedableRng, XorShiftRng};
+
+struct State {
+    rng: XorShiftRng,
+    one_in: uint,
+}
+
+thread_local!(static STATE: RefCell<Option<State>> = RefCell::new(None))
+
+/// Enables fault injection for the calling thread.
+///
+/// Each operation which may be faulted will fail with a probability of one in
+/// `one_in`, as decided by a random number generator seeded with `seed`. Any
+/// previous configuration for this thread is replaced.
+///
+/// # Panics
+///
+/// Panics if `one_in` is 0.
+pub fn enable(seed: u64, one_in: uint) {
+    assert!(one_in > 0, "the fault rate must be positive");
+    let seed = [seed as u32 | 1, (seed >> 32) as u32, 0x9e3779b9, 0x7f4a7c15];
+    STATE.with(|state| {
+        *state.borrow_mut() = Some(State {
+            rng: SeedableRng::from_seed(seed),
+            one_in: one_in,
+        });
+    });
+}
+
+/// Disables fault injection for the calling thread.
+pub fn disable() {
+    STATE.with(|state| *state.borrow_mut() = None);
+}
+
+/// Returns whether a condition variable wait should wake up spuriously.
+#[doc(hidden)]
+pub fn spurious_wakeup() -> bool { inject() }
+
+/// Returns whether a timed wait or acquisition should report a timeout without
+/// waiting.
+#[doc(hidden)]
+pub fn timeout() -> bool { inject() }
+
+/// Returns whether a `try_*` acquisition should fail without trying.
+#[doc(hidden)]
+pub fn try_fail() -> bool { inject() }
+
+fn inject() -> bool {
+    STATE.with(|state| {
+        match *state.borrow_mut() {
+            Some(ref mut state) => state.rng.gen_weighted_bool(state.one_in),
+            None => false,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use {Mutex, RWLock, Condvar, AnyCondvar};
+
+    #[test]
+    fn try_lock_fails() {
+        super::enable(1, 1);
+        let m = Mutex::new(());
+        let l = RWLock::new(());
+        assert!(m.try_lock().is_none());
+        assert!(l.try_read().is_none());
+        assert!(l.try_write().is_none());
+        super::disable();
+        assert!(m.try_lock().is_some());
+        assert!(l.try_read().is_some());
+        assert!(l.try_write().is_some());
+    }
+
+    #[test]
+    fn waits_return_early() {
+        super::enable(2, 1);
+        let m = Mutex::new(());
+        let c = Condvar::new();
+        let g = m.lock();
+        c.wait(&g);
+        c.wait_timeout(&g, Duration::days(1));
+        super::disable();
+    }
+
+    #[test]
+    fn faulted_waits_release_lock() {
+        let pair = Arc::new((Mutex::new(false), Condvar::new()));
+        let pair2 = pair.clone();
+        spawn(proc() {
+            let &(ref lock, ref cvar) = &*pair2;
+            *lock.lock() = true;
+            cvar.notify_one();
+        });
+
+        // every wait is faulted, so this only finishes if the other thread
+        // can take the lock in between
+        super::enable(3, 1);
+        let &(ref lock, ref cvar) = &*pair;
+        let g = lock.lock();
+        while !*g {
+            cvar.wait_timeout(&g, Duration::days(1));
+        }
+        super::disable();
+    }
+
+    #[test]
+    fn faulted_any_waits_release_lock() {
+        let pair = Arc::new((RWLock::new(false), AnyCondvar::new()));
+        let pair2 = pair.clone();
+        spawn(proc() {
+            let &(ref lock, ref cvar) = &*pair2;
+            *lock.write() = true;
+            cvar.notify_all();
+        });
+
+        super::enable(4, 1);
+        let &(ref lock, ref cvar) = &*pair;
+        let g = lock.read();
+        while !*g {
+            cvar.wait(&g);
+        }
+        super::disable();
+    }
+
+    #[test]
+    fn reproducible() {
+        let m = Mutex::new(());
+        let run = || {
+            super::enable(42, 2);
+            let v = range(0u, 32).map(|_| m.try_lock().is_some())
+                                 .collect::<Vec<bool>>();
+            super::disable();
+            v
+        };
+        let a = run();
+        let b = run();
+        assert_eq!(a, b);
+        assert!(a.iter().any(|&b| b));
+        assert!(a.iter().any(|&b| !b));
+    }
+}
@@ -0,0 +1,121 @@
+use std::cell::UnsafeCell;
+use std::mem;
+
+use Once;
+
+/// A value which is computed the first time it is accessed.
+///
+/// A `Lazy` is intended to be used as a `static`, and is created with the
+/// `lazy_init!` macro from the function which computes its value. The first
+/// call to `get` runs the function, and every call returns a reference to the
+/// same value. Initialization is run through a `Once`, so concurrent callers
+/// of `get` wait for the first one to finish.
+///
+/// The computed value is never dropped, just like any other `static`.
+///
+/// # Panics
+///
+/// If the initialization function panics then the `Lazy` is poisoned, and any
+/// further calls to `get` will panic.
+///
+/// # Example
+///
+/// ```
+/// #![feature(phase)]
+/// #[phase(plugin, link)] extern crate sync;
+///
+/// use std::collections::HashMap;
+/// use sync::Lazy;
+///
+/// static COLORS: Lazy<HashMap<&'static str, uint>> = lazy_init!(colors);
+///
+/// fn colors() -> HashMap<&'static str, uint> {
+///     let mut m = HashMap::new();
+///     m.insert("red", 0xff0000);
+///     m.insert("green", 0x00ff00);
+///     m
+/// }
+///
+/// fn main() {
+///     assert_eq!(*COLORS.get().get(&"red").unwrap(), 0xff0000);
+/// }
+/// ```
+pub struct Lazy<T> {
+    #[doc(hidden)]
+    pub once: Once,
+    #[doc(hidden)]
+    pub init: fn() -> T,
+    // Statics may not have destructors, so rather than an `Option<T>` this
+    // holds a reference to the value, which is leaked once computed.
+    #[doc(hidden)]
+    pub value: UnsafeCell<Option<&'static T>>,
+}
+
+impl<T: Send + Sync> Lazy<T> {
+    /// Returns a reference to the value, computing it first if this is the
+    /// first call.
+    ///
+    /// This will block the calling thread if another thread is currently
+    /// computing the value.
+    pub fn get(&'static self) -> &'static T {
+        self.once.doit(|| unsafe {
+            let value: &'static T = mem::transmute(box (self.init)());
+            *self.value.get() = Some(value);
+        });
+        unsafe { (*self.value.get()).unwrap() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::task;
+    use std::sync::atomic::{AtomicUint, INIT_ATOMIC_UINT, SeqCst};
+    use super::Lazy;
+
+    #[test]
+    fn smoke() {
+        static CALLS: AtomicUint = INIT_ATOMIC_UINT;
+        static L: Lazy<Vec<uint>> = lazy_init!(init);
+        fn init() -> Vec<uint> {
+            CALLS.fetch_add(1, SeqCst);
+            vec![1, 2, 3]
+        }
+
+        assert_eq!(CALLS.load(SeqCst), 0);
+        assert_eq!(L.get().as_slice(), [1, 2, 3].as_slice());
+        assert_eq!(L.get().as_slice(), [1, 2, 3].as_slice());
+        assert_eq!(CALLS.load(SeqCst), 1);
+    }
+
+    #[test]
+    fn stampede() {
+        static CALLS: AtomicUint = INIT_ATOMIC_UINT;
+        static L: Lazy<uint> = lazy_init!(init);
+        fn init() -> uint {
+            for _ in range(0u, 4) { task::deschedule() }
+            CALLS.fetch_add(1, SeqCst) + 10
+        }
+
+        let (tx, rx) = channel();
+        for _ in range(0u, 10) {
+            let tx = tx.clone();
+            spawn(proc() {
+                assert_eq!(*L.get(), 10);
+                tx.send(());
+            });
+        }
+        for _ in range(0u, 10) {
+            rx.recv();
+        }
+        assert_eq!(CALLS.load(SeqCst), 1);
+    }
+
+    #[test]
+    fn poisoned() {
+        static L: Lazy<uint> = lazy_init!(init);
+        fn init() -> uint { panic!() }
+
+        assert!(task::try(proc() { L.get(); }).is_err());
+        assert!(task::try(proc() { L.get(); }).is_err());
+    }
+}
@@ -31,9 +31,34 @@
 //! This crate supports a number of statically initialized primitives for use in
 //! setting up C libraries, for example. Types which are normally not statically
 //! initialized have a `Static`-prefix type to use (`StaticMutex`,
-//! `StaticCondvar`, `StaticRWLock`). A form of one-time initialization (`Once`)
-//! is also provided. All types have a `*_INIT` constant which may be used to
-//! initialize the primitive.
+//! `StaticCondvar`, `StaticRWLock`, `StaticSeqLock`). A form of one-time
+//! initialization (`Once`) is also provided. All types have a `*_INIT` constant
+//! which may be used to initialize the primitive.
+//!
+//! # Tracing
+//!
+//! When compiled with the `trace` feature, the safe primitives of this crate
+//! record their activity (acquisitions, releases, waits, and notifications)
+//! which can then be written out for viewing in a trace viewer. See the `trace`
+//! module for more information.
+//!
+//! # Fault injection
+//!
+//! When compiled with the `faults` feature, threads may opt into having
+//! condition variables wake up spuriously, timed waits time out, and `try_*`
+//! acquisitions fail at random. See the `faults` module for more information.
+//!
+//! # Valgrind
+//!
+//! When compiled with the `valgrind` feature, the primitives built in this
+//! crate annotate the synchronization they perform for Valgrind's Helgrind and
+//! DRD tools so they are not reported as data races.
+//!
+//! # Model checking
+//!
+//! When compiled with the `model` feature, the `sys` primitives are replaced
+//! with simulated versions driven by a deterministic scheduler. Tests can then
+//! be run under many (or all) thread interleavings with `sys::model::check`.
 //!
 //! # Custom primitives
 //!
@@ -42,29 +67,74 @@
 //! which do not bind to the corresponding system abstraction if one is
 //! available.
 
-#![feature(unsafe_destructor, tuple_indexing)]
+#![feature(unsafe_destructor, tuple_indexing, macro_rules)]
+#![cfg_attr(feature = "valgrind", feature(asm))]
+#![cfg_attr(target_os = "linux", feature(linkage))]
 #![deny(missing_docs)]
 
 extern crate libc;
 extern crate alloc;
 
 pub use mutex::{Mutex, MutexGuard, StaticMutex, StaticMutexGuard, MUTEX_INIT};
-pub use rwlock::{RWLock, StaticRWLock, RWLOCK_INIT};
+pub use rwlock::{RWLock, StaticRWLock, RWLOCK_INIT, Policy};
+pub use rwlock::{READER_PREFERRED_RWLOCK_INIT, WRITER_PREFERRED_RWLOCK_INIT};
+pub use rwlock::PHASE_FAIR_RWLOCK_INIT;
 pub use rwlock::{RWLockReadGuard, RWLockWriteGuard};
 pub use rwlock::{StaticRWLockReadGuard, StaticRWLockWriteGuard};
+pub use rwlock::{RWLockUpgradableGuard, StaticRWLockUpgradableGuard};
+pub use sharded::{ShardedRWLock, ShardedRWLockReadGuard};
+pub use sharded::ShardedRWLockWriteGuard;
+pub use seqlock::{SeqLock, SeqLockWriteGuard, StaticSeqLock, SEQLOCK_INIT};
+pub use seqlock::StaticSeqLockWriteGuard;
+pub use atomic_arc::AtomicArc;
 pub use condvar::{Condvar, StaticCondvar, CONDVAR_INIT, AsMutexGuard};
-pub use one::{Once, ONCE_INIT};
+pub use condvar::{MutexCondvar, MutexCondvarGuard, WaitTimeoutResult};
+pub use any_condvar::{AnyCondvar, StaticAnyCondvar, ANY_CONDVAR_INIT, RawLock};
+pub use one::{Once, ONCE_INIT, OnceState};
+pub use lazy::Lazy;
+pub use once_cell::OnceCell;
 pub use semaphore::{Semaphore, SemaphoreGuard};
 pub use barrier::Barrier;
+pub use clock::Instant;
 pub use std::sync::{Arc, Weak, TaskPool, Future, atomic};
 
+/// Constant initializer for a `Lazy`, whose value will be computed by calling
+/// the function `$init`.
+///
+/// This is intended for declaring a `static Lazy`. See `Lazy` for an example.
+#[macro_export]
+macro_rules! lazy_init {
+    ($init:expr) => (
+        $crate::Lazy {
+            once: $crate::ONCE_INIT,
+            init: $init,
+            value: ::std::cell::UnsafeCell { value: ::std::option::None },
+        }
+    )
+}
+
 pub mod sys;
+pub mod clock;
+
+#[cfg(feature = "trace")] pub mod trace;
+#[cfg(not(feature = "trace"))] #[path = "notrace.rs"] mod trace;
+#[cfg(feature = "faults")] pub mod faults;
+#[cfg(not(feature = "faults"))] #[path = "nofaults.rs"] mod faults;
+#[cfg(feature = "valgrind")] pub mod valgrind;
+#[cfg(not(feature = "valgrind"))] #[path = "novalgrind.rs"] mod valgrind;
 
 mod condvar;
+mod any_condvar;
 mod mutex;
 mod one;
+mod lazy;
+mod once_cell;
 mod rwlock;
+mod sharded;
+mod seqlock;
+mod atomic_arc;
 mod semaphore;
 mod barrier;
 
 mod poison;
+mod raw_rwlock;
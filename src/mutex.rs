@@ -1,7 +1,8 @@
 use std::cell::UnsafeCell;
 use std::kinds::marker;
 
-use {sys, poison, AsMutexGuard};
+use {sys, condvar, poison, faults, trace, AsMutexGuard, RawLock};
+use MutexCondvar;
 
 /// A mutual exclusion primitive useful for protecting shared data
 ///
@@ -66,7 +67,7 @@ pub struct Mutex<T> {
 /// ```
 pub struct StaticMutex {
     lock: sys::Mutex,
-    poison: UnsafeCell<poison::Flag>,
+    poison: poison::Flag,
 }
 
 /// An RAII implementation of a "scoped lock" of a mutex. When this structure is
@@ -95,7 +96,7 @@ pub struct StaticMutexGuard {
 /// other mutex constants.
 pub const MUTEX_INIT: StaticMutex = StaticMutex {
     lock: sys::MUTEX_INIT,
-    poison: UnsafeCell { value: poison::Flag { failed: false } },
+    poison: poison::FLAG_INIT,
 };
 
 impl<T: Send> Mutex<T> {
@@ -125,6 +126,14 @@ impl<T: Send> Mutex<T> {
         }
     }
 
+    /// Creates a new mutex in an unlocked state, paired with a condition
+    /// variable which can only be used with it.
+    ///
+    /// See `MutexCondvar` for more information.
+    pub fn with_condvar(t: T) -> MutexCondvar<T> {
+        condvar::mutex_condvar(Mutex::new(t))
+    }
+
     /// Attempts to acquire this lock.
     ///
     /// If the lock could not be acquired at this time, then `None` is returned.
@@ -161,13 +170,15 @@ impl<T: Send> Drop for Mutex<T> {
 impl StaticMutex {
     /// Acquires this lock, see `Mutex::lock`
     pub fn lock(&'static self) -> StaticMutexGuard {
+        trace::begin("Mutex::lock", &self.lock);
         unsafe { self.lock.lock() }
+        trace::end("Mutex::lock", &self.lock);
         StaticMutexGuard::new(self)
     }
 
     /// Attempts to grab this lock, see `Mutex::try_lock`
     pub fn try_lock(&'static self) -> Option<StaticMutexGuard> {
-        if unsafe { self.lock.try_lock() } {
+        if !faults::try_fail() && unsafe { self.lock.try_lock() } {
             Some(StaticMutexGuard::new(self))
         } else {
             None
@@ -199,6 +210,11 @@ impl<'mutex, T> AsMutexGuard for MutexGuard<'mutex, T> {
     unsafe fn as_mutex_guard(&self) -> &StaticMutexGuard { &self.__guard }
 }
 
+impl<'mutex, T> RawLock for MutexGuard<'mutex, T> {
+    unsafe fn unlock(&self) { self.__guard.unlock() }
+    unsafe fn relock(&self) { self.__guard.relock() }
+}
+
 impl<'mutex, T> Deref<T> for MutexGuard<'mutex, T> {
     fn deref<'a>(&'a self) -> &'a T { unsafe { &*self.__lock.data.get() } }
 }
@@ -210,15 +226,14 @@ impl<'mutex, T> DerefMut<T> for MutexGuard<'mutex, T> {
 
 impl StaticMutexGuard {
     fn new(lock: &'static StaticMutex) -> StaticMutexGuard {
-        unsafe {
-            let guard = StaticMutexGuard {
-                lock: &lock.lock,
-                marker: marker::NoSend,
-                poison: (*lock.poison.get()).borrow(),
-            };
-            guard.poison.check("mutex");
-            return guard;
-        }
+        trace::acquire("Mutex", &lock.lock);
+        let guard = StaticMutexGuard {
+            lock: &lock.lock,
+            marker: marker::NoSend,
+            poison: lock.poison.borrow(),
+        };
+        guard.poison.check("mutex");
+        guard
     }
 }
 
@@ -231,11 +246,25 @@ impl AsMutexGuard for StaticMutexGuard {
     unsafe fn as_mutex_guard(&self) -> &StaticMutexGuard { self }
 }
 
+impl RawLock for StaticMutexGuard {
+    unsafe fn unlock(&self) {
+        trace::release("Mutex", self.lock);
+        self.lock.unlock();
+    }
+
+    unsafe fn relock(&self) {
+        self.lock.lock();
+        trace::acquire("Mutex", self.lock);
+        self.poison.check("mutex");
+    }
+}
+
 #[unsafe_destructor]
 impl Drop for StaticMutexGuard {
     fn drop(&mut self) {
         unsafe {
             self.poison.done();
+            trace::release("Mutex", self.lock);
             self.lock.unlock();
         }
     }
@@ -0,0 +1,9 @@
+// Stand-ins for the hooks in `faults.rs` when the `faults` feature is
+// disabled. No faults are ever injected.
+
+#[inline(always)]
+pub fn spurious_wakeup() -> bool { false }
+#[inline(always)]
+pub fn timeout() -> bool { false }
+#[inline(always)]
+pub fn try_fail() -> bool { false }
@@ -0,0 +1,13 @@
+// Stand-ins for the hooks in `trace.rs` when the `trace` feature is disabled.
+// These all compile down to nothing.
+
+#[inline(always)]
+pub fn begin<T>(_name: &'static str, _obj: &T) {}
+#[inline(always)]
+pub fn end<T>(_name: &'static str, _obj: &T) {}
+#[inline(always)]
+pub fn acquire<T>(_name: &'static str, _obj: &T) {}
+#[inline(always)]
+pub fn release<T>(_name: &'static str, _obj: &T) {}
+#[inline(always)]
+pub fn instant<T>(_name: &'static str, _obj: &T) {}
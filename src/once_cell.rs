@@ -0,0 +1,278 @@
+use std::cell::UnsafeCell;
+
+use {sys, trace};
+use sys::atomic;
+
+/// A cell which can be written to only once.
+///
+/// Unlike `Once`, a `OnceCell` stores the value computed by its
+/// initialization, and can be embedded in any structure rather than only in a
+/// `static`. Its resources are released when it is dropped.
+///
+/// Threads calling `get_or_init` while another thread is initializing the
+/// cell block until it has finished, and then all share the same value.
+///
+/// # Example
+///
+/// ```
+/// use sync::OnceCell;
+///
+/// let cell = OnceCell::new();
+/// assert!(cell.get().is_none());
+///
+/// assert_eq!(*cell.get_or_init(|| 92i), 92);
+/// // the cell is already initialized, so this closure isn't run
+/// assert_eq!(*cell.get_or_init(|| 0i), 92);
+/// assert_eq!(cell.get(), Some(&92));
+/// ```
+pub struct OnceCell<T> {
+    // Boxed so that the system mutex never moves.
+    lock: Box<sys::Mutex>,
+    ready: atomic::AtomicBool,
+    // The id of the thread holding `lock`, see `thread_id`, or zero.
+    owner: atomic::AtomicUint,
+    value: UnsafeCell<Option<T>>,
+}
+
+// Unlocks the mutex of a `OnceCell`, even if initialization panics.
+struct Unlock<'a> {
+    lock: &'a sys::Mutex,
+    owner: &'a atomic::AtomicUint,
+}
+
+thread_local!(static THREAD_ID: u8 = 0)
+
+impl<T: Send + Sync> OnceCell<T> {
+    /// Creates a new, uninitialized cell.
+    pub fn new() -> OnceCell<T> {
+        OnceCell {
+            lock: box unsafe { sys::Mutex::new() },
+            ready: atomic::AtomicBool::new(false),
+            owner: atomic::AtomicUint::new(0),
+            value: UnsafeCell::new(None),
+        }
+    }
+
+    /// Returns a reference to the value of this cell, or `None` if it hasn't
+    /// been initialized yet.
+    ///
+    /// This never blocks, even if another thread is initializing the cell.
+    pub fn get(&self) -> Option<&T> {
+        if self.ready.load(atomic::SeqCst) {
+            unsafe { (*self.value.get()).as_ref() }
+        } else {
+            None
+        }
+    }
+
+    /// Returns a reference to the value of this cell, initializing it with
+    /// `f` if it hasn't been initialized yet.
+    ///
+    /// If another thread is initializing the cell then this blocks until it
+    /// has finished, and returns the value it computed.
+    ///
+    /// # Panics
+    ///
+    /// If `f` panics then the panic is propagated to the caller and the cell
+    /// remains uninitialized, so a later call may initialize it instead.
+    ///
+    /// This function will also panic if it is called from within the cell's
+    /// own initialization, rather than initializing the cell twice.
+    pub fn get_or_init(&self, f: || -> T) -> &T {
+        match self.get_or_try_init(|| Ok::<T, ()>(f())) {
+            Ok(value) => value,
+            Err(()) => unreachable!(),
+        }
+    }
+
+    /// Returns a reference to the value of this cell, initializing it with
+    /// `f` if it hasn't been initialized yet and `f` succeeds.
+    ///
+    /// If `f` returns an error then the cell remains uninitialized and the
+    /// error is returned. A later call, including one which was blocked
+    /// waiting for this initialization, may then initialize the cell. Only
+    /// one thread at a time runs its initialization.
+    ///
+    /// # Panics
+    ///
+    /// If `f` panics then the panic is propagated to the caller and the cell
+    /// remains uninitialized. Like `get_or_init`, this function panics if it
+    /// is called from within the cell's own initialization.
+    pub fn get_or_try_init<E>(&self, f: || -> Result<T, E>) -> Result<&T, E> {
+        match self.get() {
+            Some(value) => return Ok(value),
+            None => {}
+        }
+        trace::begin("OnceCell::get_or_init", self);
+        let _unlock = self.acquire();
+        if !self.ready.load(atomic::SeqCst) {
+            let value = match f() {
+                Ok(value) => value,
+                Err(e) => {
+                    trace::end("OnceCell::get_or_init", self);
+                    return Err(e)
+                }
+            };
+            unsafe { *self.value.get() = Some(value); }
+            self.ready.store(true, atomic::SeqCst);
+        }
+        trace::end("OnceCell::get_or_init", self);
+        Ok(self.get().unwrap())
+    }
+
+    /// Initializes this cell with `value`.
+    ///
+    /// Returns `Err` with the value if the cell was already initialized. If
+    /// another thread is initializing the cell then this blocks until it has
+    /// finished, and then fails.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if it is called from within the cell's own
+    /// initialization.
+    pub fn set(&self, value: T) -> Result<(), T> {
+        let _unlock = self.acquire();
+        if self.ready.load(atomic::SeqCst) { return Err(value) }
+        unsafe { *self.value.get() = Some(value); }
+        self.ready.store(true, atomic::SeqCst);
+        Ok(())
+    }
+
+    /// Consumes this cell, returning its value if it was initialized.
+    pub fn into_inner(self) -> Option<T> {
+        unsafe { (*self.value.get()).take() }
+    }
+
+    fn acquire(&self) -> Unlock {
+        // Locking the mutex again from the thread which holds it is undefined
+        // behavior with some system mutexes, and would let the cell be
+        // initialized twice with recursive ones, so it is refused up front.
+        let id = thread_id();
+        if self.owner.load(atomic::SeqCst) == id {
+            panic!("OnceCell initialized from within its own initialization");
+        }
+        unsafe { self.lock.lock() }
+        self.owner.store(id, atomic::SeqCst);
+        Unlock { lock: &*self.lock, owner: &self.owner }
+    }
+}
+
+#[unsafe_destructor]
+impl<T> Drop for OnceCell<T> {
+    fn drop(&mut self) {
+        unsafe { self.lock.destroy() }
+    }
+}
+
+#[unsafe_destructor]
+impl<'a> Drop for Unlock<'a> {
+    fn drop(&mut self) {
+        self.owner.store(0, atomic::SeqCst);
+        unsafe { self.lock.unlock() }
+    }
+}
+
+// Returns a non-zero id which is unique to the calling thread for as long as
+// it is running.
+fn thread_id() -> uint {
+    THREAD_ID.with(|id| id as *const u8 as uint)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUint, INIT_ATOMIC_UINT, SeqCst};
+    use std::task;
+    use super::OnceCell;
+
+    #[test]
+    fn smoke() {
+        let c = OnceCell::new();
+        assert!(c.get().is_none());
+        assert_eq!(*c.get_or_init(|| 1i), 1);
+        assert_eq!(*c.get_or_init(|| 2i), 1);
+        assert_eq!(c.get(), Some(&1));
+        assert_eq!(c.into_inner(), Some(1));
+    }
+
+    #[test]
+    fn set() {
+        let c = OnceCell::new();
+        assert_eq!(c.set(1i), Ok(()));
+        assert_eq!(c.set(2i), Err(2));
+        assert_eq!(*c.get_or_init(|| 3i), 1);
+        assert_eq!(OnceCell::<int>::new().into_inner(), None);
+    }
+
+    #[test]
+    fn stampede() {
+        static CALLS: AtomicUint = INIT_ATOMIC_UINT;
+        let c = Arc::new(OnceCell::new());
+        let (tx, rx) = channel();
+        for _ in range(0u, 10) {
+            let c = c.clone();
+            let tx = tx.clone();
+            spawn(proc() {
+                let v = c.get_or_init(|| {
+                    for _ in range(0u, 4) { task::deschedule() }
+                    CALLS.fetch_add(1, SeqCst)
+                });
+                tx.send(*v);
+            });
+        }
+        for _ in range(0u, 10) {
+            assert_eq!(rx.recv(), 0);
+        }
+        assert_eq!(CALLS.load(SeqCst), 1);
+    }
+
+    #[test]
+    fn retry_after_panic() {
+        let c = Arc::new(OnceCell::new());
+        let c2 = c.clone();
+        let res = task::try(proc() {
+            c2.get_or_init(|| -> int { panic!() });
+        });
+        assert!(res.is_err());
+        assert!(c.get().is_none());
+        assert_eq!(*c.get_or_init(|| 1i), 1);
+    }
+
+    #[test]
+    fn reentrant_init() {
+        let c = Arc::new(OnceCell::new());
+        let c2 = c.clone();
+        let res = task::try(proc() {
+            c2.get_or_init(|| *c2.get_or_init(|| 1i) + 1);
+        });
+        assert!(res.is_err());
+        // the cell is left uninitialized and unlocked
+        assert!(c.get().is_none());
+        assert_eq!(*c.get_or_init(|| 3i), 3);
+        assert_eq!(c.set(4), Err(4));
+    }
+
+    #[test]
+    fn drops_value() {
+        static DROPS: AtomicUint = INIT_ATOMIC_UINT;
+        struct D;
+        impl Drop for D {
+            fn drop(&mut self) { DROPS.fetch_add(1, SeqCst); }
+        }
+
+        let c = OnceCell::new();
+        c.get_or_init(|| D);
+        assert_eq!(DROPS.load(SeqCst), 0);
+        drop(c);
+        assert_eq!(DROPS.load(SeqCst), 1);
+    }
+
+    #[test]
+    fn get_or_try_init() {
+        let c = OnceCell::new();
+        assert_eq!(c.get_or_try_init(|| Err("bad config")), Err("bad config"));
+        assert!(c.get().is_none());
+        assert_eq!(c.get_or_try_init(|| Ok::<int, ()>(1)), Ok(&1));
+        assert_eq!(c.get_or_try_init(|| Err(())), Ok(&1));
+    }
+}
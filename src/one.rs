@@ -14,15 +14,22 @@
 //! example use case would be for initializing an FFI library.
 
 use std::int;
-use std::sync::atomic;
+use std::task;
 
-use {StaticMutex, MUTEX_INIT};
+use {sys, trace, valgrind};
+use sys::atomic;
 
 /// A synchronization primitive which can be used to run a one-time global
 /// initialization. Useful for one-time initialization for FFI or related
 /// functionality. This type can only be constructed with the `ONCE_INIT`
 /// value.
 ///
+/// # Poisoning
+///
+/// If the initialization routine panics then the `Once` is poisoned. Any
+/// further calls to `doit` will panic, but `doit_force` may be used to run
+/// the initialization again.
+///
 /// # Example
 ///
 /// ```rust
@@ -35,18 +42,33 @@ use {StaticMutex, MUTEX_INIT};
 /// });
 /// ```
 pub struct Once {
-    mutex: StaticMutex,
+    mutex: sys::Mutex,
     cnt: atomic::AtomicInt,
     lock_cnt: atomic::AtomicInt,
+    poisoned: atomic::AtomicBool,
 }
 
 /// Initialization value for static `Once` values.
 pub const ONCE_INIT: Once = Once {
-    mutex: MUTEX_INIT,
+    mutex: sys::MUTEX_INIT,
     cnt: atomic::INIT_ATOMIC_INT,
     lock_cnt: atomic::INIT_ATOMIC_INT,
+    poisoned: atomic::INIT_ATOMIC_BOOL,
 };
 
+/// State passed to the initialization routine of `Once::doit_force`.
+pub struct OnceState {
+    poisoned: bool,
+}
+
+// Releases the mutex of a `Once` as a thread leaves it. If the thread is
+// leaving without the `Once` having completed it stops being counted, and if
+// it is leaving because it panicked the `Once` is poisoned.
+struct Exit {
+    once: &'static Once,
+    completed: bool,
+}
+
 impl Once {
     /// Perform an initialization routine once and only once. The given closure
     /// will be executed if this is the first time `doit` has been called, and
@@ -57,9 +79,70 @@ impl Once {
     ///
     /// When this function returns, it is guaranteed that some initialization
     /// has run and completed (it may not be the closure specified).
+    ///
+    /// # Panics
+    ///
+    /// If the closure panics then the panic is propagated to the caller and
+    /// this `Once` is poisoned. This function will panic if the `Once` was
+    /// poisoned by a previous call, rather than running its closure.
     pub fn doit(&'static self, f: ||) {
+        self.call(false, |_| { f(); true })
+    }
+
+    /// Perform a fallible initialization routine once and only once.
+    ///
+    /// This is the same as `doit`, except that if the closure returns an
+    /// error then the `Once` is not considered to have completed. The error is
+    /// returned to the caller, and a later call to `doit` or `try_doit` will
+    /// run its closure to retry the initialization. Threads which were blocked
+    /// waiting for the failed routine will likewise run their own closures,
+    /// one at a time.
+    ///
+    /// Returns `Ok` if some initialization routine has completed, which may
+    /// not have been this closure.
+    ///
+    /// # Panics
+    ///
+    /// This function panics in the same situations as `doit`. A routine which
+    /// returns an error does not poison the `Once`.
+    pub fn try_doit<E>(&'static self, f: || -> Result<(), E>) -> Result<(), E> {
+        let mut err = None;
+        self.call(false, |_| {
+            match f() {
+                Ok(()) => true,
+                Err(e) => { err = Some(e); false }
+            }
+        });
+        match err {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Perform an initialization routine once and only once, even if a
+    /// previous initialization routine panicked.
+    ///
+    /// This is the same as `doit`, except that a poisoned `Once` will run this
+    /// closure instead of panicking. The closure is told whether the `Once`
+    /// was poisoned, so it can clean up after the failed initialization. If
+    /// the closure completes then the `Once` is no longer poisoned.
+    pub fn doit_force(&'static self, f: |&OnceState|) {
+        self.call(true, |state| { f(state); true })
+    }
+
+    /// Returns whether an initialization routine has run and completed.
+    ///
+    /// This returns `false` if the only routines which have run panicked.
+    pub fn is_completed(&self) -> bool {
+        self.cnt.load(atomic::SeqCst) < 0
+    }
+
+    // Runs `f` unless the `Once` has completed, where `f` returns whether the
+    // `Once` is now complete.
+    fn call(&'static self, force: bool, f: |&OnceState| -> bool) {
         // Optimize common path: load is much cheaper than fetch_add.
         if self.cnt.load(atomic::SeqCst) < 0 {
+            valgrind::happens_after(self);
             return
         }
 
@@ -89,36 +172,78 @@ impl Once {
         // initialization routine has completed because otherwise new threads
         // calling `doit` will return immediately before the initialization has
         // completed.
+        //
+        // A thread which leaves the mutex without the initialization having
+        // completed, because its routine panicked or failed, will never take
+        // part in this cleanup. It stops being counted before it releases the
+        // mutex. The count can't have been swapped out at that point, as that
+        // only happens with the mutex held.
 
         let prev = self.cnt.fetch_add(1, atomic::SeqCst);
         if prev < 0 {
             // Make sure we never overflow, we'll never have int::MIN
             // simultaneous calls to `doit` to make this value go back to 0
             self.cnt.store(int::MIN, atomic::SeqCst);
+            valgrind::happens_after(self);
             return
         }
 
         // If the count is negative, then someone else finished the job,
         // otherwise we run the job and record how many people will try to grab
         // this lock
-        let guard = self.mutex.lock();
+        trace::begin("Once::doit", self);
+        unsafe { self.mutex.lock() }
+        let mut exit = Exit { once: self, completed: false };
         if self.cnt.load(atomic::SeqCst) > 0 {
-            f();
-            let prev = self.cnt.swap(int::MIN, atomic::SeqCst);
-            self.lock_cnt.store(prev, atomic::SeqCst);
+            let state = OnceState {
+                poisoned: self.poisoned.load(atomic::SeqCst),
+            };
+            if state.poisoned && !force {
+                panic!("Once instance has previously been poisoned");
+            }
+            if f(&state) {
+                valgrind::happens_before(self);
+                self.poisoned.store(false, atomic::SeqCst);
+                let prev = self.cnt.swap(int::MIN, atomic::SeqCst);
+                self.lock_cnt.store(prev, atomic::SeqCst);
+                exit.completed = true;
+            }
+        } else {
+            exit.completed = true;
         }
-        drop(guard);
+        let completed = exit.completed;
+        drop(exit);
+        trace::end("Once::doit", self);
 
         // Last one out cleans up after everyone else, no leaks!
-        if self.lock_cnt.fetch_add(-1, atomic::SeqCst) == 1 {
+        if completed && self.lock_cnt.fetch_add(-1, atomic::SeqCst) == 1 {
             unsafe { self.mutex.destroy() }
         }
     }
 }
 
+impl OnceState {
+    /// Returns whether a previous initialization routine of the `Once`
+    /// panicked.
+    pub fn poisoned(&self) -> bool { self.poisoned }
+}
+
+impl Drop for Exit {
+    fn drop(&mut self) {
+        if !self.completed {
+            if task::failing() {
+                self.once.poisoned.store(true, atomic::SeqCst);
+            }
+            self.once.cnt.fetch_sub(1, atomic::SeqCst);
+        }
+        unsafe { self.once.mutex.unlock() }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use std::task;
+    use sys::atomic;
     use super::{ONCE_INIT, Once};
 
     #[test]
@@ -164,4 +289,138 @@ mod test {
             rx.recv();
         }
     }
+
+    #[test]
+    fn poison_bad() {
+        static O: Once = ONCE_INIT;
+
+        // poison the once
+        let t = task::try(proc() {
+            O.doit(|| panic!());
+        });
+        assert!(t.is_err());
+        assert!(!O.is_completed());
+
+        // poisoning propagates
+        let t = task::try(proc() {
+            O.doit(|| {});
+        });
+        assert!(t.is_err());
+
+        // we can subvert poisoning, however
+        let mut called = false;
+        O.doit_force(|p| {
+            called = true;
+            assert!(p.poisoned());
+        });
+        assert!(called);
+        assert!(O.is_completed());
+
+        // once any success happens, we stop propagating the poison
+        O.doit(|| {});
+    }
+
+    #[test]
+    fn wait_for_force_to_finish() {
+        static O: Once = ONCE_INIT;
+
+        // poison the once
+        let t = task::try(proc() {
+            O.doit(|| panic!());
+        });
+        assert!(t.is_err());
+
+        // make sure someone's waiting inside the once via a force
+        let (tx1, rx1) = channel();
+        let (tx2, rx2) = channel();
+        let t1 = task::try_future(proc() {
+            O.doit_force(|p| {
+                tx1.send(());
+                assert!(p.poisoned());
+                rx2.recv();
+            });
+        });
+
+        rx1.recv();
+
+        // put another waiter on the once
+        let t2 = task::try_future(proc() {
+            let mut called = false;
+            O.doit(|| {
+                called = true;
+            });
+            assert!(!called);
+        });
+
+        tx2.send(());
+
+        assert!(t1.unwrap().is_ok());
+        assert!(t2.unwrap().is_ok());
+    }
+
+    #[test]
+    fn is_completed() {
+        static O: Once = ONCE_INIT;
+        assert!(!O.is_completed());
+        O.doit(|| assert!(!O.is_completed()));
+        assert!(O.is_completed());
+    }
+
+    #[test]
+    fn try_doit_retries() {
+        static O: Once = ONCE_INIT;
+
+        assert_eq!(O.try_doit(|| Err("no device")), Err("no device"));
+        assert!(!O.is_completed());
+
+        let mut runs = 0i;
+        assert_eq!(O.try_doit(|| -> Result<(), ()> { runs += 1; Ok(()) }),
+                   Ok(()));
+        assert!(O.is_completed());
+        assert_eq!(O.try_doit(|| -> Result<(), ()> { runs += 1; Err(()) }),
+                   Ok(()));
+        assert_eq!(runs, 1);
+    }
+
+    #[test]
+    fn try_doit_waiters_retry() {
+        static O: Once = ONCE_INIT;
+        static mut RUNS: uint = 0;
+
+        // the first attempt fails while others are waiting on it, after which
+        // exactly one of them initializes the once
+        let (tx1, rx1) = channel();
+        let (tx2, rx2) = channel();
+        let t1 = task::try_future(proc() {
+            O.try_doit(|| {
+                tx1.send(());
+                rx2.recv();
+                Err(())
+            })
+        });
+        rx1.recv();
+
+        let mut waiters = Vec::new();
+        for _ in range(0u, 4) {
+            waiters.push(task::try_future(proc() {
+                O.try_doit(|| -> Result<(), ()> {
+                    unsafe { RUNS += 1; }
+                    Ok(())
+                })
+            }));
+        }
+        // every waiter counts itself in before blocking on the mutex held by
+        // the first attempt, so only let that attempt fail once all have
+        while O.cnt.load(atomic::SeqCst) < 5 {
+            task::deschedule();
+        }
+        tx2.send(());
+
+        assert_eq!(t1.unwrap().unwrap(), Err(()));
+        for w in waiters.into_iter() {
+            assert_eq!(w.unwrap().unwrap(), Ok(()));
+        }
+        assert_eq!(unsafe { RUNS }, 1);
+        assert!(O.is_completed());
+    }
 }
@@ -1,28 +1,36 @@
 use std::task::failing;
 
-pub struct Flag { pub failed: bool }
+use sys::atomic;
+
+// The flag is atomic as readers of an `RWLock` may check or set it while
+// sharing the lock with each other.
+pub struct Flag { failed: atomic::AtomicBool }
+
+pub const FLAG_INIT: Flag = Flag { failed: atomic::INIT_ATOMIC_BOOL };
 
 impl Flag {
-    pub fn borrow(&mut self) -> Guard {
-        Guard { flag: &mut self.failed, failing: failing() }
+    pub fn borrow(&self) -> Guard {
+        Guard { flag: &self.failed, failing: failing() }
     }
+
+    pub fn get(&self) -> bool { self.failed.load(atomic::SeqCst) }
 }
 
 pub struct Guard<'a> {
-    flag: &'a mut bool,
+    flag: &'a atomic::AtomicBool,
     failing: bool,
 }
 
 impl<'a> Guard<'a> {
     pub fn check(&self, name: &str) {
-        if *self.flag {
+        if self.flag.load(atomic::SeqCst) {
             panic!("poisoned {} - another task failed inside", name);
         }
     }
 
     pub fn done(&mut self) {
         if !self.failing && failing() {
-            *self.flag = true;
+            self.flag.store(true, atomic::SeqCst);
         }
     }
 }
@@ -0,0 +1,439 @@
+//! The raw reader-writer lock underlying `StaticRWLock`.
+//!
+//! With `Policy::System` this is just the system rwlock. Every other policy is
+//! implemented in userspace on top of a system mutex and condition variables,
+//! which is slower but behaves the same across platforms.
+//!
+//! Upgradable readers are tracked here for every policy. At most one thread
+//! holds the upgradable slot at a time, and plain readers and writers never
+//! wait for it. Once the upgradable reader starts upgrading, though, the
+//! writer-preferred and phase-fair policies hold new readers back for it just
+//! as they would for a waiting writer.
+//!
+//! The system rwlock can't be upgraded or downgraded in place, so with
+//! `Policy::System` the read or write access is released and reacquired, and
+//! any writer which gets in while this hand-off is in progress releases the
+//! lock again and waits for it to complete.
+
+use std::cell::UnsafeCell;
+
+use {sys, clock, Instant};
+use sys::atomic;
+use rwlock::Policy;
+
+pub struct RawRWLock {
+    policy: Policy,
+    sys: sys::RWLock,
+    lock: sys::Mutex,
+    readers: sys::Condvar,
+    writers: sys::Condvar,
+    // Waited on for the upgradable slot, by an upgrade waiting for the other
+    // readers to leave, and by system writers backing off from a hand-off.
+    upgrade: sys::Condvar,
+    // System policy only: the number of upgrades and downgrades which have
+    // released the system rwlock and not yet reacquired it.
+    handoffs: atomic::AtomicUint,
+    state: UnsafeCell<State>,
+}
+
+// The state of the rwlock, protected by `RawRWLock::lock`. Only `upgradable`
+// is used with `Policy::System`.
+struct State {
+    // Whether a thread holds, or is acquiring, upgradable read access.
+    upgradable: bool,
+    // Whether the upgradable reader is waiting for the other readers to leave.
+    upgrading: bool,
+    // Number of threads holding read access.
+    readers: uint,
+    // Whether a thread holds write access.
+    writer: bool,
+    // Number of threads blocked in `write`.
+    waiting_writers: uint,
+    // Phase-fair only: the number of write phases which have completed, the
+    // number of readers blocked until the current one completes, and the
+    // number of readers admitted by the last completed one which have yet to
+    // acquire the lock.
+    phase: uint,
+    waiting_readers: uint,
+    admitted: uint,
+}
+
+const STATE_INIT: State = State {
+    upgradable: false,
+    upgrading: false,
+    readers: 0,
+    writer: false,
+    waiting_writers: 0,
+    phase: 0,
+    waiting_readers: 0,
+    admitted: 0,
+};
+
+pub const SYSTEM: RawRWLock = RawRWLock {
+    policy: Policy::System,
+    sys: sys::RWLOCK_INIT,
+    lock: sys::MUTEX_INIT,
+    readers: sys::CONDVAR_INIT,
+    writers: sys::CONDVAR_INIT,
+    upgrade: sys::CONDVAR_INIT,
+    handoffs: atomic::INIT_ATOMIC_UINT,
+    state: UnsafeCell { value: STATE_INIT },
+};
+
+pub const READER_PREFERRED: RawRWLock = RawRWLock {
+    policy: Policy::ReaderPreferred,
+    sys: sys::RWLOCK_INIT,
+    lock: sys::MUTEX_INIT,
+    readers: sys::CONDVAR_INIT,
+    writers: sys::CONDVAR_INIT,
+    upgrade: sys::CONDVAR_INIT,
+    handoffs: atomic::INIT_ATOMIC_UINT,
+    state: UnsafeCell { value: STATE_INIT },
+};
+
+pub const WRITER_PREFERRED: RawRWLock = RawRWLock {
+    policy: Policy::WriterPreferred,
+    sys: sys::RWLOCK_INIT,
+    lock: sys::MUTEX_INIT,
+    readers: sys::CONDVAR_INIT,
+    writers: sys::CONDVAR_INIT,
+    upgrade: sys::CONDVAR_INIT,
+    handoffs: atomic::INIT_ATOMIC_UINT,
+    state: UnsafeCell { value: STATE_INIT },
+};
+
+pub const PHASE_FAIR: RawRWLock = RawRWLock {
+    policy: Policy::PhaseFair,
+    sys: sys::RWLOCK_INIT,
+    lock: sys::MUTEX_INIT,
+    readers: sys::CONDVAR_INIT,
+    writers: sys::CONDVAR_INIT,
+    upgrade: sys::CONDVAR_INIT,
+    handoffs: atomic::INIT_ATOMIC_UINT,
+    state: UnsafeCell { value: STATE_INIT },
+};
+
+impl RawRWLock {
+    pub fn policy(&self) -> Policy { self.policy }
+
+    pub unsafe fn read(&self) {
+        if self.policy == Policy::System { return self.sys.read() }
+        self.read_with(|cvar| { cvar.wait(&self.lock); true });
+    }
+
+    // Acquires read access, returning whether it was acquired before the
+    // calling thread's clock reached `deadline`.
+    pub unsafe fn read_deadline(&self, deadline: Instant) -> bool {
+        if self.policy == Policy::System {
+            return if clock::is_system() {
+                self.sys.read_timeout(deadline.duration_since(clock::now()))
+            } else {
+                clock::poll_until(deadline, || self.sys.try_read())
+            }
+        }
+        self.read_with(|cvar| clock::wait_deadline(cvar, &self.lock, deadline))
+    }
+
+    pub unsafe fn try_read(&self) -> bool {
+        if self.policy == Policy::System { return self.sys.try_read() }
+        self.lock.lock();
+        let state = &mut *self.state.get();
+        let ret = self.can_read(state);
+        if ret {
+            state.readers += 1;
+        }
+        self.lock.unlock();
+        ret
+    }
+
+    pub unsafe fn write(&self) {
+        if self.policy == Policy::System {
+            self.sys_write_with(|| { self.sys.write(); true },
+                                |cvar| { cvar.wait(&self.lock); true });
+            return
+        }
+        self.write_with(|cvar| { cvar.wait(&self.lock); true });
+    }
+
+    // Acquires write access, returning whether it was acquired before the
+    // calling thread's clock reached `deadline`.
+    pub unsafe fn write_deadline(&self, deadline: Instant) -> bool {
+        if self.policy == Policy::System {
+            let lock = || {
+                if clock::is_system() {
+                    let dur = deadline.duration_since(clock::now());
+                    self.sys.write_timeout(dur)
+                } else {
+                    clock::poll_until(deadline, || self.sys.try_write())
+                }
+            };
+            return self.sys_write_with(lock, |cvar| {
+                clock::wait_deadline(cvar, &self.lock, deadline)
+            })
+        }
+        self.write_with(|cvar| clock::wait_deadline(cvar, &self.lock, deadline))
+    }
+
+    pub unsafe fn try_write(&self) -> bool {
+        if self.policy == Policy::System {
+            return self.sys_write_with(|| self.sys.try_write(), |_| false)
+        }
+        self.lock.lock();
+        let state = &mut *self.state.get();
+        let ret = can_write(state);
+        if ret {
+            state.writer = true;
+        }
+        self.lock.unlock();
+        ret
+    }
+
+    pub unsafe fn read_unlock(&self) {
+        if self.policy == Policy::System { return self.sys.read_unlock() }
+        self.lock.lock();
+        let state = &mut *self.state.get();
+        state.readers -= 1;
+        if state.upgrading && state.readers == 1 && state.admitted == 0 {
+            self.upgrade.notify_all();
+        }
+        if can_write(state) && state.waiting_writers > 0 {
+            self.writers.notify_one();
+        }
+        self.lock.unlock();
+    }
+
+    pub unsafe fn write_unlock(&self) {
+        if self.policy == Policy::System { return self.sys.write_unlock() }
+        self.lock.lock();
+        let state = &mut *self.state.get();
+        state.writer = false;
+        if self.policy == Policy::PhaseFair {
+            state.phase += 1;
+            state.admitted += state.waiting_readers;
+            state.waiting_readers = 0;
+        }
+        // Readers are only woken up if they are able to make progress, which
+        // with a writer-preferring lock is only once there are no writers.
+        if self.policy != Policy::WriterPreferred ||
+           state.waiting_writers == 0 {
+            self.readers.notify_all();
+        }
+        if can_write(state) && state.waiting_writers > 0 {
+            self.writers.notify_one();
+        }
+        self.lock.unlock();
+    }
+
+    // Acquires upgradable read access, which is read access held by at most
+    // one thread at a time.
+    pub unsafe fn upgradable_read(&self) {
+        self.lock.lock();
+        let state = &mut *self.state.get();
+        while state.upgradable {
+            self.upgrade.wait(&self.lock);
+        }
+        state.upgradable = true;
+        self.lock.unlock();
+        self.read();
+    }
+
+    pub unsafe fn try_upgradable_read(&self) -> bool {
+        self.lock.lock();
+        let state = &mut *self.state.get();
+        let ret = !state.upgradable;
+        if ret {
+            state.upgradable = true;
+        }
+        self.lock.unlock();
+        if ret && !self.try_read() {
+            self.release_upgradable();
+            return false
+        }
+        ret
+    }
+
+    pub unsafe fn upgradable_unlock(&self) {
+        self.read_unlock();
+        self.release_upgradable();
+    }
+
+    // Exchanges upgradable read access for write access, without any other
+    // writer acquiring the lock in between.
+    pub unsafe fn upgrade(&self) {
+        if self.policy == Policy::System {
+            self.handoffs.fetch_add(1, atomic::SeqCst);
+            self.sys.read_unlock();
+            self.sys.write();
+            self.lock.lock();
+            (*self.state.get()).upgradable = false;
+            self.handoffs.fetch_sub(1, atomic::SeqCst);
+            self.upgrade.notify_all();
+            self.lock.unlock();
+            return
+        }
+        self.lock.lock();
+        let state = &mut *self.state.get();
+        // Other writers can't acquire the lock while this thread is still a
+        // reader, so it only has to wait for the other readers to leave.
+        state.upgrading = true;
+        while state.readers > 1 || state.admitted > 0 {
+            self.upgrade.wait(&self.lock);
+        }
+        state.upgrading = false;
+        state.upgradable = false;
+        state.readers = 0;
+        state.writer = true;
+        self.upgrade.notify_all();
+        self.lock.unlock();
+    }
+
+    // Exchanges write access for read access, without any other writer
+    // acquiring the lock in between.
+    pub unsafe fn downgrade(&self) {
+        if self.policy == Policy::System {
+            self.handoffs.fetch_add(1, atomic::SeqCst);
+            self.sys.write_unlock();
+            self.sys.read();
+            self.lock.lock();
+            self.handoffs.fetch_sub(1, atomic::SeqCst);
+            self.upgrade.notify_all();
+            self.lock.unlock();
+            return
+        }
+        self.lock.lock();
+        let state = &mut *self.state.get();
+        state.writer = false;
+        state.readers += 1;
+        if self.policy == Policy::PhaseFair {
+            state.phase += 1;
+            state.admitted += state.waiting_readers;
+            state.waiting_readers = 0;
+        }
+        if self.policy != Policy::WriterPreferred ||
+           state.waiting_writers == 0 {
+            self.readers.notify_all();
+        }
+        self.lock.unlock();
+    }
+
+    pub unsafe fn destroy(&self) {
+        self.sys.destroy();
+        self.lock.destroy();
+        self.readers.destroy();
+        self.writers.destroy();
+        self.upgrade.destroy();
+    }
+
+    unsafe fn release_upgradable(&self) {
+        self.lock.lock();
+        (*self.state.get()).upgradable = false;
+        self.upgrade.notify_all();
+        self.lock.unlock();
+    }
+
+    // Acquires write access to the system rwlock with `lock`. If an upgrade or
+    // downgrade is releasing and reacquiring the lock, the write access is
+    // given up again and `wait` is used to block until the hand-off completes.
+    // If `wait` returns `false` the acquisition is abandoned.
+    unsafe fn sys_write_with(&self, lock: || -> bool,
+                             wait: |&sys::Condvar| -> bool) -> bool {
+        loop {
+            if !lock() { return false }
+            if self.handoffs.load(atomic::SeqCst) == 0 { return true }
+            self.sys.write_unlock();
+            self.lock.lock();
+            while self.handoffs.load(atomic::SeqCst) > 0 {
+                if !wait(&self.upgrade) &&
+                   self.handoffs.load(atomic::SeqCst) > 0 {
+                    self.lock.unlock();
+                    return false
+                }
+            }
+            self.lock.unlock();
+        }
+    }
+
+    // Acquires read access with a userspace policy, blocking with `wait`
+    // whenever the lock is unavailable. If `wait` returns `false` the
+    // acquisition is abandoned once the lock is still unavailable.
+    unsafe fn read_with(&self, wait: |&sys::Condvar| -> bool) -> bool {
+        self.lock.lock();
+        let state = &mut *self.state.get();
+        let mut acquired = true;
+        if !self.can_read(state) {
+            if self.policy == Policy::PhaseFair {
+                // Wait for the current write phase to complete, after which
+                // this reader is admitted ahead of any other writer.
+                let phase = state.phase;
+                state.waiting_readers += 1;
+                while state.phase == phase {
+                    if !wait(&self.readers) && state.phase == phase {
+                        state.waiting_readers -= 1;
+                        acquired = false;
+                        break
+                    }
+                }
+                if acquired {
+                    state.admitted -= 1;
+                }
+            } else {
+                while !self.can_read(state) {
+                    if !wait(&self.readers) && !self.can_read(state) {
+                        acquired = false;
+                        break
+                    }
+                }
+            }
+        }
+        if acquired {
+            state.readers += 1;
+        }
+        self.lock.unlock();
+        acquired
+    }
+
+    // Acquires write access with a userspace policy, see `read_with`.
+    unsafe fn write_with(&self, wait: |&sys::Condvar| -> bool) -> bool {
+        self.lock.lock();
+        let state = &mut *self.state.get();
+        let mut acquired = true;
+        state.waiting_writers += 1;
+        while !can_write(state) {
+            if !wait(&self.writers) && !can_write(state) {
+                acquired = false;
+                break
+            }
+        }
+        state.waiting_writers -= 1;
+        if acquired {
+            state.writer = true;
+        } else if !state.writer && state.waiting_writers == 0 {
+            // Readers may have been held back for this writer, which is no
+            // longer coming. With a phase-fair lock they are waiting for a
+            // write phase to complete, so complete an empty one.
+            if self.policy == Policy::PhaseFair {
+                state.phase += 1;
+                state.admitted += state.waiting_readers;
+                state.waiting_readers = 0;
+            }
+            self.readers.notify_all();
+        }
+        self.lock.unlock();
+        acquired
+    }
+
+    // Whether a new reader may acquire the lock right away. An upgrade in
+    // progress counts as a waiting writer.
+    fn can_read(&self, state: &State) -> bool {
+        match self.policy {
+            Policy::ReaderPreferred => !state.writer,
+            _ => {
+                !state.writer && state.waiting_writers == 0 && !state.upgrading
+            }
+        }
+    }
+}
+
+fn can_write(state: &State) -> bool {
+    !state.writer && state.readers == 0 && state.admitted == 0
+}
@@ -1,7 +1,10 @@
 use std::kinds::marker;
 use std::cell::UnsafeCell;
+use std::mem;
+use std::time::Duration;
 
-use {sys, poison};
+use {clock, poison, faults, raw_rwlock, trace, RawLock, Instant};
+use sys::atomic;
 
 /// A reader-writer lock
 ///
@@ -17,9 +20,14 @@ use {sys, poison};
 /// to allow access to the contained of the lock.
 ///
 /// RWLocks, like Mutexes, will become poisoned on panics. Note, however, that
-/// an RWLock may only be poisoned if a panic occurs while it is locked
-/// exclusively (write mode). If a panic occurs in any reader, then the lock
-/// will not be poisoned.
+/// by default an RWLock may only be poisoned if a panic occurs while it is
+/// locked exclusively (write mode). If a panic occurs in any reader, then the
+/// lock will not be poisoned unless this has been enabled with
+/// `set_poison_readers`.
+///
+/// By default an RWLock makes no guarantee about whether readers or writers are
+/// preferred, as this is up to the system rwlock. An explicit `Policy` can be
+/// chosen with `RWLock::with_policy`.
 ///
 /// # Example
 ///
@@ -72,16 +80,64 @@ pub struct RWLock<T> {
 /// unsafe { LOCK.destroy() } // free all resources
 /// ```
 pub struct StaticRWLock {
-    inner: sys::RWLock,
-    poison: UnsafeCell<poison::Flag>,
+    inner: raw_rwlock::RawRWLock,
+    poison: poison::Flag,
+    poison_readers: atomic::AtomicBool,
 }
 
 /// Constant initialization for a statically-initialized rwlock.
+///
+/// This uses `Policy::System`.
 pub const RWLOCK_INIT: StaticRWLock = StaticRWLock {
-    inner: sys::RWLOCK_INIT,
-    poison: UnsafeCell { value: poison::Flag { failed: false } },
+    inner: raw_rwlock::SYSTEM,
+    poison: poison::FLAG_INIT,
+    poison_readers: atomic::INIT_ATOMIC_BOOL,
+};
+
+/// Constant initialization for a statically-initialized rwlock using
+/// `Policy::ReaderPreferred`.
+pub const READER_PREFERRED_RWLOCK_INIT: StaticRWLock = StaticRWLock {
+    inner: raw_rwlock::READER_PREFERRED,
+    poison: poison::FLAG_INIT,
+    poison_readers: atomic::INIT_ATOMIC_BOOL,
+};
+
+/// Constant initialization for a statically-initialized rwlock using
+/// `Policy::WriterPreferred`.
+pub const WRITER_PREFERRED_RWLOCK_INIT: StaticRWLock = StaticRWLock {
+    inner: raw_rwlock::WRITER_PREFERRED,
+    poison: poison::FLAG_INIT,
+    poison_readers: atomic::INIT_ATOMIC_BOOL,
 };
 
+/// Constant initialization for a statically-initialized rwlock using
+/// `Policy::PhaseFair`.
+pub const PHASE_FAIR_RWLOCK_INIT: StaticRWLock = StaticRWLock {
+    inner: raw_rwlock::PHASE_FAIR,
+    poison: poison::FLAG_INIT,
+    poison_readers: atomic::INIT_ATOMIC_BOOL,
+};
+
+/// The policy an RWLock uses to decide between readers and writers waiting to
+/// acquire it.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum Policy {
+    /// Use the system rwlock, whose behavior is platform dependent. For
+    /// example, rwlocks on glibc prefer readers while SRW locks on Windows do
+    /// not.
+    System,
+    /// New readers may acquire the lock as long as no writer holds it, so
+    /// writers can be starved by a continuous stream of readers.
+    ReaderPreferred,
+    /// New readers may not acquire the lock while any writer is waiting for
+    /// it, so readers can be starved by a continuous stream of writers.
+    WriterPreferred,
+    /// Readers and writers alternate: new readers wait behind a waiting
+    /// writer, but all readers which were waiting when a writer releases the
+    /// lock acquire it before the next writer. Neither side can be starved.
+    PhaseFair,
+}
+
 /// RAII structure used to release the shared read access of a lock when
 /// dropped.
 #[must_use]
@@ -98,29 +154,81 @@ pub struct RWLockWriteGuard<'a, T: 'a> {
     __guard: StaticRWLockWriteGuard,
 }
 
+/// RAII structure used to release the upgradable read access of a lock when
+/// dropped.
+#[must_use]
+pub struct RWLockUpgradableGuard<'a, T: 'a> {
+    __lock: &'a RWLock<T>,
+    __guard: StaticRWLockUpgradableGuard,
+}
+
 /// RAII structure used to release the shared read access of a lock when
 /// dropped.
 #[must_use]
 pub struct StaticRWLockReadGuard {
-    lock: &'static sys::RWLock,
+    lock: &'static StaticRWLock,
     marker: marker::NoSend,
+    poison: Option<poison::Guard<'static>>,
 }
 
 /// RAII structure used to release the exclusive write access of a lock when
 /// dropped.
 #[must_use]
 pub struct StaticRWLockWriteGuard {
-    lock: &'static sys::RWLock,
+    lock: &'static StaticRWLock,
     marker: marker::NoSend,
     poison: poison::Guard<'static>,
 }
 
+/// RAII structure used to release the upgradable read access of a lock when
+/// dropped.
+#[must_use]
+pub struct StaticRWLockUpgradableGuard {
+    lock: &'static StaticRWLock,
+    marker: marker::NoSend,
+    poison: Option<poison::Guard<'static>>,
+}
+
 impl<T: Send + Sync> RWLock<T> {
     /// Creates a new instance of an RWLock which is unlocked and read to go.
     pub fn new(t: T) -> RWLock<T> {
         RWLock { inner: box RWLOCK_INIT, data: UnsafeCell::new(t) }
     }
 
+    /// Creates a new instance of an RWLock which uses the specified policy to
+    /// choose between waiting readers and writers.
+    ///
+    /// All policies other than `Policy::System` are implemented in userspace
+    /// on top of a mutex and condition variables, which is consistent across
+    /// platforms but slower than the system rwlock.
+    pub fn with_policy(t: T, policy: Policy) -> RWLock<T> {
+        let inner = match policy {
+            Policy::System => box RWLOCK_INIT,
+            Policy::ReaderPreferred => box READER_PREFERRED_RWLOCK_INIT,
+            Policy::WriterPreferred => box WRITER_PREFERRED_RWLOCK_INIT,
+            Policy::PhaseFair => box PHASE_FAIR_RWLOCK_INIT,
+        };
+        RWLock { inner: inner, data: UnsafeCell::new(t) }
+    }
+
+    /// Returns the policy this lock was created with.
+    pub fn policy(&self) -> Policy { self.inner.policy() }
+
+    /// Sets whether a panic while holding shared read access poisons this
+    /// lock.
+    ///
+    /// This is disabled by default, as readers normally can't leave the data
+    /// in an inconsistent state. It should be enabled if readers modify the
+    /// data through interior mutability. Only read guards, including
+    /// upgradable ones, acquired after this call are affected.
+    pub fn set_poison_readers(&self, poison: bool) {
+        self.inner.set_poison_readers(poison)
+    }
+
+    /// Returns whether a panic while holding shared read access poisons this
+    /// lock.
+    pub fn poisons_readers(&self) -> bool { self.inner.poisons_readers() }
+
     /// Locks this rwlock with shared read access, blocking the current thread
     /// until it can be acquired.
     ///
@@ -166,6 +274,38 @@ impl<T: Send + Sync> RWLock<T> {
         }
     }
 
+    /// Locks this rwlock with shared read access, blocking the current thread
+    /// for at most `dur`.
+    ///
+    /// Returns `Some` of an RAII guard if shared access was acquired, or
+    /// `None` if the timeout elapsed first. The timeout is measured by the
+    /// calling thread's clock.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the RWLock is poisoned. A panic will only
+    /// occur if the lock is acquired.
+    #[inline]
+    pub fn read_timeout(&self, dur: Duration) -> Option<RWLockReadGuard<T>> {
+        self.read_deadline(clock::now() + dur)
+    }
+
+    /// Locks this rwlock with shared read access, blocking the current thread
+    /// until at most `deadline`.
+    ///
+    /// Returns `None` if the deadline was reached before shared access could
+    /// be acquired. See `read_timeout`.
+    #[inline]
+    pub fn read_deadline(&self, deadline: Instant)
+                         -> Option<RWLockReadGuard<T>> {
+        unsafe {
+            let lock: &'static StaticRWLock = &*(&*self.inner as *const _);
+            lock.read_deadline(deadline).map(|guard| {
+                RWLockReadGuard::new(self, guard)
+            })
+        }
+    }
+
     /// Lock this rwlock with exclusive write access, blocking the current
     /// thread until it can be acquired.
     ///
@@ -208,6 +348,79 @@ impl<T: Send + Sync> RWLock<T> {
             })
         }
     }
+
+    /// Lock this rwlock with exclusive write access, blocking the current
+    /// thread for at most `dur`.
+    ///
+    /// Returns `Some` of an RAII guard if write access was acquired, or `None`
+    /// if the timeout elapsed first, for example because another thread is
+    /// stuck holding the lock. The timeout is measured by the calling thread's
+    /// clock.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the RWLock is poisoned. A panic will only
+    /// occur if the lock is acquired.
+    #[inline]
+    pub fn write_timeout(&self, dur: Duration) -> Option<RWLockWriteGuard<T>> {
+        self.write_deadline(clock::now() + dur)
+    }
+
+    /// Lock this rwlock with exclusive write access, blocking the current
+    /// thread until at most `deadline`.
+    ///
+    /// Returns `None` if the deadline was reached before write access could be
+    /// acquired. See `write_timeout`.
+    #[inline]
+    pub fn write_deadline(&self, deadline: Instant)
+                          -> Option<RWLockWriteGuard<T>> {
+        unsafe {
+            let lock: &'static StaticRWLock = &*(&*self.inner as *const _);
+            lock.write_deadline(deadline).map(|guard| {
+                RWLockWriteGuard::new(self, guard)
+            })
+        }
+    }
+
+    /// Locks this rwlock with upgradable read access, blocking the current
+    /// thread until it can be acquired.
+    ///
+    /// Upgradable read access is shared with any number of plain readers, but
+    /// is exclusive with writers and other upgradable readers. The returned
+    /// guard can later be atomically upgraded to write access with
+    /// `RWLockUpgradableGuard::upgrade`, without any other writer being able
+    /// to acquire the lock in between.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the RWLock is poisoned, in the same way as
+    /// `read`.
+    #[inline]
+    pub fn upgradable_read(&self) -> RWLockUpgradableGuard<T> {
+        unsafe {
+            let lock: &'static StaticRWLock = &*(&*self.inner as *const _);
+            RWLockUpgradableGuard::new(self, lock.upgradable_read())
+        }
+    }
+
+    /// Attempt to acquire this lock with upgradable read access.
+    ///
+    /// This function does not block, returning `None` if a call to
+    /// `upgradable_read` would otherwise block.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the RWLock is poisoned. A panic will only
+    /// occur if the lock is acquired.
+    #[inline]
+    pub fn try_upgradable_read(&self) -> Option<RWLockUpgradableGuard<T>> {
+        unsafe {
+            let lock: &'static StaticRWLock = &*(&*self.inner as *const _);
+            lock.try_upgradable_read().map(|guard| {
+                RWLockUpgradableGuard::new(self, guard)
+            })
+        }
+    }
 }
 
 #[unsafe_destructor]
@@ -224,7 +437,9 @@ impl StaticRWLock {
     /// See `RWLock::read`.
     #[inline]
     pub fn read(&'static self) -> StaticRWLockReadGuard {
+        trace::begin("RWLock::read", &self.inner);
         unsafe { self.inner.read() }
+        trace::end("RWLock::read", &self.inner);
         StaticRWLockReadGuard::new(self)
     }
 
@@ -233,7 +448,34 @@ impl StaticRWLock {
     /// See `RWLock::try_read`.
     #[inline]
     pub fn try_read(&'static self) -> Option<StaticRWLockReadGuard> {
-        if unsafe { self.inner.try_read() } {
+        if !faults::try_fail() && unsafe { self.inner.try_read() } {
+            Some(StaticRWLockReadGuard::new(self))
+        } else {
+            None
+        }
+    }
+
+    /// Locks this rwlock with shared read access, blocking the current thread
+    /// for at most `dur`.
+    ///
+    /// See `RWLock::read_timeout`.
+    #[inline]
+    pub fn read_timeout(&'static self, dur: Duration)
+                        -> Option<StaticRWLockReadGuard> {
+        self.read_deadline(clock::now() + dur)
+    }
+
+    /// Locks this rwlock with shared read access, blocking the current thread
+    /// until at most `deadline`.
+    ///
+    /// See `RWLock::read_deadline`.
+    pub fn read_deadline(&'static self, deadline: Instant)
+                         -> Option<StaticRWLockReadGuard> {
+        if faults::timeout() { return None }
+        trace::begin("RWLock::read_deadline", &self.inner);
+        let acquired = unsafe { self.inner.read_deadline(deadline) };
+        trace::end("RWLock::read_deadline", &self.inner);
+        if acquired {
             Some(StaticRWLockReadGuard::new(self))
         } else {
             None
@@ -246,7 +488,9 @@ impl StaticRWLock {
     /// See `RWLock::write`.
     #[inline]
     pub fn write(&'static self) -> StaticRWLockWriteGuard {
+        trace::begin("RWLock::write", &self.inner);
         unsafe { self.inner.write() }
+        trace::end("RWLock::write", &self.inner);
         StaticRWLockWriteGuard::new(self)
     }
 
@@ -255,13 +499,83 @@ impl StaticRWLock {
     /// See `RWLock::try_write`.
     #[inline]
     pub fn try_write(&'static self) -> Option<StaticRWLockWriteGuard> {
-        if unsafe { self.inner.try_write() } {
+        if !faults::try_fail() && unsafe { self.inner.try_write() } {
             Some(StaticRWLockWriteGuard::new(self))
         } else {
             None
         }
     }
 
+    /// Lock this rwlock with exclusive write access, blocking the current
+    /// thread for at most `dur`.
+    ///
+    /// See `RWLock::write_timeout`.
+    #[inline]
+    pub fn write_timeout(&'static self, dur: Duration)
+                         -> Option<StaticRWLockWriteGuard> {
+        self.write_deadline(clock::now() + dur)
+    }
+
+    /// Lock this rwlock with exclusive write access, blocking the current
+    /// thread until at most `deadline`.
+    ///
+    /// See `RWLock::write_deadline`.
+    pub fn write_deadline(&'static self, deadline: Instant)
+                          -> Option<StaticRWLockWriteGuard> {
+        if faults::timeout() { return None }
+        trace::begin("RWLock::write_deadline", &self.inner);
+        let acquired = unsafe { self.inner.write_deadline(deadline) };
+        trace::end("RWLock::write_deadline", &self.inner);
+        if acquired {
+            Some(StaticRWLockWriteGuard::new(self))
+        } else {
+            None
+        }
+    }
+
+    /// Locks this rwlock with upgradable read access, blocking the current
+    /// thread until it can be acquired.
+    ///
+    /// See `RWLock::upgradable_read`.
+    #[inline]
+    pub fn upgradable_read(&'static self) -> StaticRWLockUpgradableGuard {
+        trace::begin("RWLock::upgradable_read", &self.inner);
+        unsafe { self.inner.upgradable_read() }
+        trace::end("RWLock::upgradable_read", &self.inner);
+        StaticRWLockUpgradableGuard::new(self)
+    }
+
+    /// Attempt to acquire this lock with upgradable read access.
+    ///
+    /// See `RWLock::try_upgradable_read`.
+    #[inline]
+    pub fn try_upgradable_read(&'static self)
+                               -> Option<StaticRWLockUpgradableGuard> {
+        if faults::try_fail() { return None }
+        if unsafe { self.inner.try_upgradable_read() } {
+            Some(StaticRWLockUpgradableGuard::new(self))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the policy this lock was created with.
+    pub fn policy(&self) -> Policy { self.inner.policy() }
+
+    /// Sets whether a panic while holding shared read access poisons this
+    /// lock.
+    ///
+    /// See `RWLock::set_poison_readers`.
+    pub fn set_poison_readers(&self, poison: bool) {
+        self.poison_readers.store(poison, atomic::SeqCst)
+    }
+
+    /// Returns whether a panic while holding shared read access poisons this
+    /// lock.
+    pub fn poisons_readers(&self) -> bool {
+        self.poison_readers.load(atomic::SeqCst)
+    }
+
     /// Deallocate all resources associated with this static lock.
     ///
     /// This method is unsafe to call as there is no guarantee that there are no
@@ -269,7 +583,7 @@ impl StaticRWLock {
     /// of this lock. This method is required to be called to not leak memory on
     /// all platforms.
     pub unsafe fn destroy(&'static self) {
-        self.inner.destroy()
+        self.inner.destroy();
     }
 }
 
@@ -284,6 +598,39 @@ impl<'rwlock, T> RWLockWriteGuard<'rwlock, T> {
            -> RWLockWriteGuard<T> {
         RWLockWriteGuard { __lock: lock, __guard: guard }
     }
+
+    /// Atomically downgrades this guard to shared read access.
+    ///
+    /// Other readers may acquire the lock as soon as this returns, but no
+    /// writer can acquire it between the write access being released and the
+    /// read access being acquired.
+    ///
+    /// If the current thread is panicking, then the lock is poisoned as if
+    /// this guard had been dropped.
+    pub fn downgrade(self) -> RWLockReadGuard<'rwlock, T> {
+        let RWLockWriteGuard { __lock, __guard } = self;
+        RWLockReadGuard::new(__lock, __guard.downgrade())
+    }
+}
+
+impl<'rwlock, T> RWLockUpgradableGuard<'rwlock, T> {
+    fn new(lock: &RWLock<T>, guard: StaticRWLockUpgradableGuard)
+           -> RWLockUpgradableGuard<T> {
+        RWLockUpgradableGuard { __lock: lock, __guard: guard }
+    }
+
+    /// Atomically upgrades this guard to exclusive write access, blocking the
+    /// current thread until all other readers have released the lock.
+    ///
+    /// No other writer can acquire the lock between this guard's read access
+    /// being released and the write access being acquired. While this waits,
+    /// the lock's policy treats it as a waiting writer, so with
+    /// `Policy::WriterPreferred` or `Policy::PhaseFair` new readers are held
+    /// back until the upgrade completes.
+    pub fn upgrade(self) -> RWLockWriteGuard<'rwlock, T> {
+        let RWLockUpgradableGuard { __lock, __guard } = self;
+        RWLockWriteGuard::new(__lock, __guard.upgrade())
+    }
 }
 
 impl<'rwlock, T> Deref<T> for RWLockReadGuard<'rwlock, T> {
@@ -292,38 +639,164 @@ impl<'rwlock, T> Deref<T> for RWLockReadGuard<'rwlock, T> {
 impl<'rwlock, T> Deref<T> for RWLockWriteGuard<'rwlock, T> {
     fn deref(&self) -> &T { unsafe { &*self.__lock.data.get() } }
 }
+impl<'rwlock, T> Deref<T> for RWLockUpgradableGuard<'rwlock, T> {
+    fn deref(&self) -> &T { unsafe { &*self.__lock.data.get() } }
+}
 impl<'rwlock, T> DerefMut<T> for RWLockWriteGuard<'rwlock, T> {
     fn deref_mut(&mut self) -> &mut T { unsafe { &mut *self.__lock.data.get() } }
 }
 
 impl StaticRWLockReadGuard {
     fn new(lock: &'static StaticRWLock) -> StaticRWLockReadGuard {
+        trace::acquire("RWLock (read)", &lock.inner);
         let guard = StaticRWLockReadGuard {
-            lock: &lock.inner,
+            lock: lock,
             marker: marker::NoSend,
+            poison: StaticRWLockReadGuard::poison(lock),
         };
-        unsafe { (*lock.poison.get()).borrow().check("rwlock"); }
+        lock.poison.borrow().check("rwlock");
         return guard;
     }
+
+    // Readers only hold on to the poison flag if they are able to poison it.
+    fn poison(lock: &'static StaticRWLock) -> Option<poison::Guard<'static>> {
+        if lock.poisons_readers() {
+            Some(lock.poison.borrow())
+        } else {
+            None
+        }
+    }
 }
 impl StaticRWLockWriteGuard {
     fn new(lock: &'static StaticRWLock) -> StaticRWLockWriteGuard {
-        unsafe {
-            let guard = StaticRWLockWriteGuard {
-                lock: &lock.inner,
-                marker: marker::NoSend,
-                poison: (*lock.poison.get()).borrow(),
-            };
-            guard.poison.check("rwlock");
-            return guard;
+        trace::acquire("RWLock (write)", &lock.inner);
+        let guard = StaticRWLockWriteGuard {
+            lock: lock,
+            marker: marker::NoSend,
+            poison: lock.poison.borrow(),
+        };
+        guard.poison.check("rwlock");
+        guard
+    }
+}
+impl StaticRWLockWriteGuard {
+    /// Atomically downgrades this guard to shared read access.
+    ///
+    /// See `RWLockWriteGuard::downgrade`.
+    pub fn downgrade(mut self) -> StaticRWLockReadGuard {
+        self.poison.done();
+        let lock = self.lock;
+        unsafe { mem::forget(self); }
+        trace::release("RWLock (write)", &lock.inner);
+        trace::begin("RWLock::downgrade", &lock.inner);
+        unsafe { lock.inner.downgrade() }
+        trace::end("RWLock::downgrade", &lock.inner);
+        // The lock wasn't poisoned when the write access was acquired, and
+        // can only have been poisoned by this thread since, so it isn't
+        // checked again.
+        trace::acquire("RWLock (read)", &lock.inner);
+        StaticRWLockReadGuard {
+            lock: lock,
+            marker: marker::NoSend,
+            poison: StaticRWLockReadGuard::poison(lock),
+        }
+    }
+}
+
+impl StaticRWLockUpgradableGuard {
+    fn new(lock: &'static StaticRWLock) -> StaticRWLockUpgradableGuard {
+        trace::acquire("RWLock (upgradable)", &lock.inner);
+        let guard = StaticRWLockUpgradableGuard {
+            lock: lock,
+            marker: marker::NoSend,
+            poison: StaticRWLockReadGuard::poison(lock),
+        };
+        lock.poison.borrow().check("rwlock");
+        return guard;
+    }
+
+    /// Atomically upgrades this guard to exclusive write access, blocking the
+    /// current thread until all other readers have released the lock.
+    ///
+    /// See `RWLockUpgradableGuard::upgrade`.
+    pub fn upgrade(mut self) -> StaticRWLockWriteGuard {
+        match self.poison {
+            Some(ref mut poison) => poison.done(),
+            None => {}
         }
+        let lock = self.lock;
+        unsafe { mem::forget(self); }
+        trace::release("RWLock (upgradable)", &lock.inner);
+        trace::begin("RWLock::upgrade", &lock.inner);
+        unsafe { lock.inner.upgrade() }
+        trace::end("RWLock::upgrade", &lock.inner);
+        StaticRWLockWriteGuard::new(lock)
     }
 }
 
+impl RawLock for StaticRWLockReadGuard {
+    unsafe fn unlock(&self) {
+        trace::release("RWLock (read)", &self.lock.inner);
+        self.lock.inner.read_unlock();
+    }
+
+    unsafe fn relock(&self) {
+        self.lock.inner.read();
+        trace::acquire("RWLock (read)", &self.lock.inner);
+        self.lock.poison.borrow().check("rwlock");
+    }
+}
+
+impl RawLock for StaticRWLockWriteGuard {
+    unsafe fn unlock(&self) {
+        trace::release("RWLock (write)", &self.lock.inner);
+        self.lock.inner.write_unlock();
+    }
+
+    unsafe fn relock(&self) {
+        self.lock.inner.write();
+        trace::acquire("RWLock (write)", &self.lock.inner);
+        self.poison.check("rwlock");
+    }
+}
+
+impl RawLock for StaticRWLockUpgradableGuard {
+    unsafe fn unlock(&self) {
+        trace::release("RWLock (upgradable)", &self.lock.inner);
+        self.lock.inner.upgradable_unlock();
+    }
+
+    unsafe fn relock(&self) {
+        self.lock.inner.upgradable_read();
+        trace::acquire("RWLock (upgradable)", &self.lock.inner);
+        self.lock.poison.borrow().check("rwlock");
+    }
+}
+
+impl<'rwlock, T> RawLock for RWLockReadGuard<'rwlock, T> {
+    unsafe fn unlock(&self) { self.__guard.unlock() }
+    unsafe fn relock(&self) { self.__guard.relock() }
+}
+
+impl<'rwlock, T> RawLock for RWLockWriteGuard<'rwlock, T> {
+    unsafe fn unlock(&self) { self.__guard.unlock() }
+    unsafe fn relock(&self) { self.__guard.relock() }
+}
+
+impl<'rwlock, T> RawLock for RWLockUpgradableGuard<'rwlock, T> {
+    unsafe fn unlock(&self) { self.__guard.unlock() }
+    unsafe fn relock(&self) { self.__guard.relock() }
+}
+
 #[unsafe_destructor]
 impl Drop for StaticRWLockReadGuard {
     fn drop(&mut self) {
-        unsafe { self.lock.read_unlock(); }
+        match self.poison {
+            Some(ref mut poison) => poison.done(),
+            None => {}
+        }
+        trace::release("RWLock (read)", &self.lock.inner);
+        unsafe { self.lock.inner.read_unlock(); }
     }
 }
 
@@ -331,7 +804,20 @@ impl Drop for StaticRWLockReadGuard {
 impl Drop for StaticRWLockWriteGuard {
     fn drop(&mut self) {
         self.poison.done();
-        unsafe { self.lock.write_unlock(); }
+        trace::release("RWLock (write)", &self.lock.inner);
+        unsafe { self.lock.inner.write_unlock(); }
+    }
+}
+
+#[unsafe_destructor]
+impl Drop for StaticRWLockUpgradableGuard {
+    fn drop(&mut self) {
+        match self.poison {
+            Some(ref mut poison) => poison.done(),
+            None => {}
+        }
+        trace::release("RWLock (upgradable)", &self.lock.inner);
+        unsafe { self.lock.inner.upgradable_unlock(); }
     }
 }
 
@@ -340,7 +826,11 @@ mod tests {
     use std::rand::{mod, Rng};
     use std::sync::Arc;
     use std::task;
-    use super::{RWLock, StaticRWLock, RWLOCK_INIT};
+    use std::time::Duration;
+    use std::sync::atomic::{AtomicBool, INIT_ATOMIC_BOOL, SeqCst};
+    use super::{RWLock, StaticRWLock, RWLOCK_INIT, Policy};
+    use Barrier;
+    use clock::{mod, MockClock};
 
     #[test]
     fn smoke() {
@@ -361,6 +851,234 @@ mod tests {
         unsafe { R.destroy(); }
     }
 
+    #[test]
+    fn upgradable() {
+        let l = RWLock::new(1i);
+        {
+            let u = l.upgradable_read();
+            let r = l.read();
+            assert_eq!(*u, 1);
+            assert_eq!(*r, 1);
+            assert!(l.try_upgradable_read().is_none());
+            assert!(l.try_write().is_none());
+        }
+        let u = l.upgradable_read();
+        let mut w = u.upgrade();
+        *w += 1;
+        drop(w);
+        assert_eq!(*l.read(), 2);
+    }
+
+    #[test]
+    fn policies() {
+        for &policy in [Policy::System, Policy::ReaderPreferred,
+                        Policy::WriterPreferred, Policy::PhaseFair].iter() {
+            let l = RWLock::with_policy(0i, policy);
+            assert_eq!(l.policy(), policy);
+            drop((l.read(), l.read()));
+            assert!(l.try_write().is_some());
+            {
+                let _r = l.read();
+                assert!(l.try_write().is_none());
+                assert!(l.try_read().is_some());
+            }
+            *l.write() += 1;
+            assert_eq!(*l.read(), 1);
+        }
+    }
+
+    // Readers continuously hold the lock, overlapping with each other, until a
+    // writer manages to get in.
+    fn writer_not_starved(policy: Policy) {
+        static N: uint = 4;
+        let arc = Arc::new(RWLock::with_policy(false, policy));
+        let (tx, rx) = channel();
+        for _ in range(0, N) {
+            let arc2 = arc.clone();
+            let tx = tx.clone();
+            spawn(proc() {
+                tx.send(());
+                loop {
+                    let g = arc2.read();
+                    if *g { break }
+                    task::deschedule();
+                }
+                tx.send(());
+            });
+        }
+        for _ in range(0, N) { rx.recv(); }
+        *arc.write() = true;
+        for _ in range(0, N) { rx.recv(); }
+    }
+
+    #[test]
+    fn writer_preferred_not_starved() {
+        writer_not_starved(Policy::WriterPreferred);
+    }
+
+    #[test]
+    fn phase_fair_not_starved() {
+        writer_not_starved(Policy::PhaseFair);
+    }
+
+    // Like `writer_not_starved`, but the writer is an upgrading reader.
+    fn upgrade_not_starved(policy: Policy) {
+        static N: uint = 4;
+        let arc = Arc::new(RWLock::with_policy(false, policy));
+        let (tx, rx) = channel();
+        for _ in range(0, N) {
+            let arc2 = arc.clone();
+            let tx = tx.clone();
+            spawn(proc() {
+                tx.send(());
+                loop {
+                    let g = arc2.read();
+                    if *g { break }
+                    task::deschedule();
+                }
+                tx.send(());
+            });
+        }
+        for _ in range(0, N) { rx.recv(); }
+        let u = arc.upgradable_read();
+        *u.upgrade() = true;
+        for _ in range(0, N) { rx.recv(); }
+    }
+
+    #[test]
+    fn writer_preferred_upgrade_not_starved() {
+        upgrade_not_starved(Policy::WriterPreferred);
+    }
+
+    #[test]
+    fn phase_fair_upgrade_not_starved() {
+        upgrade_not_starved(Policy::PhaseFair);
+    }
+
+    #[test]
+    fn phase_fair_readers_not_starved() {
+        static DONE: AtomicBool = INIT_ATOMIC_BOOL;
+        let arc = Arc::new(RWLock::with_policy(0u, Policy::PhaseFair));
+        let (tx, rx) = channel();
+        for _ in range(0u, 2) {
+            let arc2 = arc.clone();
+            let tx = tx.clone();
+            spawn(proc() {
+                while !DONE.load(SeqCst) {
+                    let mut g = arc2.write();
+                    *g += 1;
+                    task::deschedule();
+                }
+                tx.send(());
+            });
+        }
+        for _ in range(0u, 10) {
+            drop(arc.read());
+        }
+        DONE.store(true, SeqCst);
+        rx.recv();
+        rx.recv();
+    }
+
+    #[test]
+    fn downgrade() {
+        static R: StaticRWLock = RWLOCK_INIT;
+        let w = R.write();
+        let r = w.downgrade();
+        assert!(R.try_write().is_none());
+        let r2 = R.read();
+        drop((r, r2));
+        drop(R.write());
+        unsafe { R.destroy(); }
+
+        let l = RWLock::new(1i);
+        let mut w = l.write();
+        *w += 1;
+        let r = w.downgrade();
+        assert_eq!(*r, 2);
+        assert_eq!(*l.read(), 2);
+    }
+
+    #[test]
+    fn downgrade_excludes_writers() {
+        let arc = Arc::new(RWLock::new(0i));
+        let mut children = Vec::new();
+        for _ in range(0u, 5) {
+            let arc2 = arc.clone();
+            children.push(task::try_future(proc() {
+                for _ in range(0u, 100) {
+                    let mut w = arc2.write();
+                    *w += 1;
+                    let seen = *w;
+                    let r = w.downgrade();
+                    assert_eq!(*r, seen);
+                }
+            }));
+        }
+        for r in children.iter_mut() {
+            assert!(r.get_ref().is_ok());
+        }
+        assert_eq!(*arc.read(), 500);
+    }
+
+    #[test]
+    fn upgrade_excludes_writers() {
+        for &policy in [Policy::System, Policy::ReaderPreferred,
+                        Policy::WriterPreferred, Policy::PhaseFair].iter() {
+            upgrade_excludes_writers_with(policy);
+        }
+    }
+
+    fn upgrade_excludes_writers_with(policy: Policy) {
+        let arc = Arc::new(RWLock::with_policy(0i, policy));
+        let mut children = Vec::new();
+        for _ in range(0u, 5) {
+            let arc2 = arc.clone();
+            children.push(task::try_future(proc() {
+                for _ in range(0u, 100) {
+                    let u = arc2.upgradable_read();
+                    let seen = *u;
+                    let mut w = u.upgrade();
+                    // nobody else could have written in between
+                    assert_eq!(*w, seen);
+                    *w += 1;
+                }
+            }));
+            let arc2 = arc.clone();
+            children.push(task::try_future(proc() {
+                for _ in range(0u, 100) {
+                    drop(arc2.read());
+                    let mut w = arc2.write();
+                    *w += 1;
+                    let seen = *w;
+                    assert_eq!(*w.downgrade(), seen);
+                }
+            }));
+        }
+        for r in children.iter_mut() {
+            assert!(r.get_ref().is_ok());
+        }
+        assert_eq!(*arc.read(), 1000);
+    }
+
+    #[test]
+    fn upgradable_does_not_hide_writers() {
+        let l = Arc::new(RWLock::with_policy((), Policy::WriterPreferred));
+        let u = l.upgradable_read();
+        let l2 = l.clone();
+        let (tx, rx) = channel();
+        spawn(proc() {
+            drop(l2.write());
+            tx.send(());
+        });
+        // once the writer is waiting, new readers hold back for it
+        while l.try_read().is_some() {
+            task::deschedule();
+        }
+        drop(u);
+        rx.recv();
+    }
+
     #[test]
     fn frob() {
         static R: StaticRWLock = RWLOCK_INIT;
@@ -436,6 +1154,158 @@ mod tests {
         assert_eq!(*lock, 1);
     }
 
+    #[test]
+    #[should_fail]
+    fn test_rw_arc_poison_readers_rw() {
+        let arc = Arc::new(RWLock::new(1i));
+        arc.set_poison_readers(true);
+        let arc2 = arc.clone();
+        let _ = task::try(proc() {
+            let lock = arc2.read();
+            assert_eq!(*lock, 2);
+        });
+        let lock = arc.write();
+        assert_eq!(*lock, 1);
+    }
+
+    #[test]
+    fn poison_readers_static() {
+        static R: StaticRWLock = RWLOCK_INIT;
+        assert!(!R.poisons_readers());
+        R.set_poison_readers(true);
+        assert!(R.poisons_readers());
+        // guards acquired before the option is cleared still poison the lock
+        let res = task::try(proc() {
+            let _g = R.read();
+            R.set_poison_readers(false);
+            panic!();
+        });
+        assert!(res.is_err());
+        let res = task::try(proc() { drop(R.read()); });
+        assert!(res.is_err());
+        unsafe { R.destroy(); }
+    }
+
+    #[test]
+    fn poison_upgradable_readers() {
+        static R: StaticRWLock = RWLOCK_INIT;
+        let res = task::try(proc() {
+            let _g = R.upgradable_read();
+            panic!();
+        });
+        assert!(res.is_err());
+        drop(R.upgradable_read());
+
+        R.set_poison_readers(true);
+        let res = task::try(proc() {
+            let _g = R.upgradable_read();
+            panic!();
+        });
+        assert!(res.is_err());
+        let res = task::try(proc() { drop(R.read()); });
+        assert!(res.is_err());
+        unsafe { R.destroy(); }
+    }
+
+    #[test]
+    fn poison_readers_concurrent() {
+        let arc = Arc::new(RWLock::new(()));
+        arc.set_poison_readers(true);
+        let barrier = Arc::new(Barrier::new(5));
+        let mut children = Vec::new();
+        for i in range(0u, 5) {
+            let arc2 = arc.clone();
+            let barrier = barrier.clone();
+            children.push(task::try_future(proc() {
+                let _r = arc2.read();
+                // every reader is inside the lock when the first one panics
+                barrier.wait();
+                if i == 0 { panic!() }
+            }));
+        }
+        let mut failed = 0u;
+        for r in children.iter_mut() {
+            if r.get_ref().is_err() { failed += 1; }
+        }
+        assert_eq!(failed, 1);
+        let res = task::try(proc() { drop(arc.read()); });
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn timeouts() {
+        let dur = Duration::milliseconds(1);
+        for &policy in [Policy::System, Policy::ReaderPreferred,
+                        Policy::WriterPreferred, Policy::PhaseFair].iter() {
+            let l = Arc::new(RWLock::with_policy((), policy));
+            let l2 = l.clone();
+            let g = l.write();
+            let res = task::try(proc() {
+                l2.read_timeout(dur).is_none() &&
+                    l2.write_timeout(dur).is_none()
+            });
+            assert!(res.unwrap());
+            drop(g);
+            drop(l.read_timeout(dur).unwrap());
+            drop(l.write_timeout(dur).unwrap());
+        }
+    }
+
+    #[test]
+    fn static_timeouts() {
+        static R: StaticRWLock = RWLOCK_INIT;
+        let dur = Duration::milliseconds(1);
+        let g = R.read();
+        let res = task::try(proc() {
+            R.write_timeout(dur).is_none()
+        });
+        assert!(res.unwrap());
+        drop(R.read_timeout(dur).unwrap());
+        drop(g);
+        drop(R.write_timeout(dur).unwrap());
+        unsafe { R.destroy(); }
+    }
+
+    #[test]
+    fn write_timeout_releases_readers() {
+        for &policy in [Policy::WriterPreferred, Policy::PhaseFair].iter() {
+            let l = Arc::new(RWLock::with_policy((), policy));
+            let r = l.read();
+            let l2 = l.clone();
+            let writer = task::try_future(proc() {
+                l2.write_timeout(Duration::milliseconds(50)).is_none()
+            });
+            // this reader queues up behind the writer, and must be let in
+            // once the writer gives up
+            let l3 = l.clone();
+            let reader = task::try_future(proc() { drop(l3.read()); });
+            assert!(writer.unwrap().unwrap());
+            assert!(reader.unwrap().is_ok());
+            drop(r);
+        }
+    }
+
+    #[test]
+    fn mock_clock_timeout() {
+        let clock = MockClock::new();
+        let clock2 = clock.clone();
+        let l = Arc::new(RWLock::new(()));
+        let l2 = l.clone();
+        let (tx, rx) = channel();
+        let g = l.write();
+        spawn(proc() {
+            clock::set(clock2);
+            let deadline = clock::now() + Duration::days(1);
+            tx.send(());
+            assert!(l2.read_deadline(deadline).is_none());
+            tx.send(());
+        });
+        rx.recv();
+        clock.advance(Duration::days(1));
+        rx.recv();
+        drop(g);
+    }
+
     #[test]
     fn test_rw_arc() {
         let arc = Arc::new(RWLock::new(0i));
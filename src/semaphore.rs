@@ -1,4 +1,4 @@
-use {Mutex, Condvar};
+use {trace, valgrind, Mutex, Condvar};
 
 /// A counting, blocking, semaphore.
 ///
@@ -57,11 +57,12 @@ impl Semaphore {
     /// This method will block until the internal count of the semaphore is at
     /// least 1.
     pub fn acquire(&self) {
+        trace::begin("Semaphore::acquire", self);
         let mut count = self.lock.lock();
-        while *count <= 0 {
-            self.cvar.wait(&count);
-        }
+        self.cvar.wait_while(&count, || *count <= 0);
         *count -= 1;
+        valgrind::happens_after(self);
+        trace::end("Semaphore::acquire", self);
     }
 
     /// Release a resource from this semaphore.
@@ -69,8 +70,16 @@ impl Semaphore {
     /// This will increment the number of resources in this semaphore by 1 and
     /// will notify any pending waiters in `acquire` or `access` if necessary.
     pub fn release(&self) {
-        *self.lock.lock() += 1;
-        self.cvar.notify_one();
+        trace::instant("Semaphore::release", self);
+        valgrind::happens_before(self);
+        let mut count = self.lock.lock();
+        *count += 1;
+        // Notifying with the lock held means the condvar sees every thread
+        // blocked in `acquire`, so it won't signal if there are none. There's
+        // also no point waking anyone if the count is still not positive.
+        if *count > 0 {
+            self.cvar.notify_one();
+        }
     }
 
     /// Acquires a resource of this semaphore, returning an RAII guard to
@@ -0,0 +1,366 @@
+use std::cell::UnsafeCell;
+use std::kinds::marker;
+use std::ptr;
+use std::task;
+
+use {sys, poison, faults, trace};
+use sys::atomic;
+
+/// A sequence lock, protecting small `Copy` data which is written rarely and
+/// read often.
+///
+/// Readers of a sequence lock never block writers or write to shared memory.
+/// Instead they optimistically copy the data out of the lock, and retry if a
+/// writer was active in the meantime. Writers are serialized with one another
+/// through an internal mutex, and bump a sequence counter before and after
+/// modifying the data so readers can detect their interference.
+///
+/// This makes reads very cheap, but means they may have to retry many times if
+/// the data is written frequently. Reads copy the whole of the data, so it
+/// should also be kept small.
+///
+/// # Poisoning
+///
+/// If a writer panics then the data may have been left partially modified, so
+/// the lock is poisoned and any further reads or writes will panic.
+///
+/// # Example
+///
+/// ```
+/// use sync::SeqLock;
+///
+/// let lock = SeqLock::new((0u, 0u));
+///
+/// {
+///     let mut w = lock.write();
+///     *w = (1, 2);
+/// } // the write is published here
+///
+/// assert_eq!(lock.read(), (1, 2));
+/// ```
+pub struct SeqLock<T> {
+    inner: Box<StaticSeqLock>,
+    data: UnsafeCell<T>,
+}
+
+/// A sequence lock suitable for use in a `static`.
+///
+/// Like `StaticRWLock`, this does not contain the data which it protects.
+/// Readers instead bracket their reads of the data with `read_begin` and
+/// `read_retry`, retrying while `read_retry` returns `true`. The internal
+/// resources of this lock must be manually deallocated.
+///
+/// # Example
+///
+/// ```
+/// use std::sync::atomic::{AtomicUint, INIT_ATOMIC_UINT, Relaxed};
+/// use sync::{StaticSeqLock, SEQLOCK_INIT};
+///
+/// static LOCK: StaticSeqLock = SEQLOCK_INIT;
+/// static SECS: AtomicUint = INIT_ATOMIC_UINT;
+/// static NANOS: AtomicUint = INIT_ATOMIC_UINT;
+///
+/// {
+///     let _g = LOCK.write();
+///     SECS.store(1, Relaxed);
+///     NANOS.store(500, Relaxed);
+/// }
+///
+/// let mut time;
+/// loop {
+///     let seq = LOCK.read_begin();
+///     time = (SECS.load(Relaxed), NANOS.load(Relaxed));
+///     if !LOCK.read_retry(seq) { break }
+/// }
+/// assert_eq!(time, (1, 500));
+/// unsafe { LOCK.destroy() }
+/// ```
+pub struct StaticSeqLock {
+    lock: sys::Mutex,
+    // Odd while a writer is modifying the data.
+    seq: atomic::AtomicInt,
+    poison: poison::Flag,
+}
+
+/// Constant initializer for a statically allocated sequence lock.
+pub const SEQLOCK_INIT: StaticSeqLock = StaticSeqLock {
+    lock: sys::MUTEX_INIT,
+    seq: atomic::INIT_ATOMIC_INT,
+    poison: poison::FLAG_INIT,
+};
+
+/// RAII structure used to publish a write to a sequence lock when dropped.
+#[must_use]
+pub struct SeqLockWriteGuard<'a, T: 'a> {
+    __lock: &'a SeqLock<T>,
+    __guard: StaticSeqLockWriteGuard,
+}
+
+/// RAII structure used to publish a write to a static sequence lock when
+/// dropped.
+#[must_use]
+pub struct StaticSeqLockWriteGuard {
+    lock: &'static StaticSeqLock,
+    marker: marker::NoSend,
+    poison: poison::Guard<'static>,
+}
+
+impl<T: Copy + Send> SeqLock<T> {
+    /// Creates a new sequence lock protecting the given data.
+    pub fn new(t: T) -> SeqLock<T> {
+        SeqLock { inner: box SEQLOCK_INIT, data: UnsafeCell::new(t) }
+    }
+
+    /// Returns a copy of the data protected by this lock.
+    ///
+    /// This never blocks writers, but will retry for as long as writers
+    /// interfere with the copy.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the lock is poisoned.
+    pub fn read(&self) -> T {
+        let lock = self.inner();
+        loop {
+            let seq = lock.read_begin();
+            // The copy may be torn by a concurrent writer, in which case it is
+            // discarded without ever being looked at.
+            let t = unsafe { ptr::read(self.data.get() as *const T) };
+            if !lock.read_retry(seq) { return t }
+        }
+    }
+
+    /// Locks this sequence lock for writing, blocking the current thread until
+    /// any other writer has finished.
+    ///
+    /// Readers may still run while the returned guard is held, but none will
+    /// return until the guard has been dropped.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the lock is poisoned.
+    pub fn write(&self) -> SeqLockWriteGuard<T> {
+        SeqLockWriteGuard { __lock: self, __guard: self.inner().write() }
+    }
+
+    /// Attempts to lock this sequence lock for writing.
+    ///
+    /// Returns `None` if another writer holds the lock. This function does not
+    /// block.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the lock is poisoned. A panic will only
+    /// occur if the lock is acquired.
+    pub fn try_write(&self) -> Option<SeqLockWriteGuard<T>> {
+        self.inner().try_write().map(|guard| {
+            SeqLockWriteGuard { __lock: self, __guard: guard }
+        })
+    }
+
+    fn inner(&self) -> &'static StaticSeqLock {
+        unsafe { &*(&*self.inner as *const _) }
+    }
+}
+
+#[unsafe_destructor]
+impl<T> Drop for SeqLock<T> {
+    fn drop(&mut self) {
+        unsafe { self.inner.lock.destroy() }
+    }
+}
+
+impl StaticSeqLock {
+    /// Begins a read of the data protected by this lock, returning the
+    /// sequence number to pass to `read_retry` once the data has been read.
+    ///
+    /// This waits for any writer which is currently modifying the data.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the lock is poisoned.
+    pub fn read_begin(&'static self) -> uint {
+        loop {
+            let seq = self.seq.load(atomic::SeqCst);
+            if seq & 1 == 0 {
+                self.poison.borrow().check("seqlock");
+                return seq as uint
+            }
+            task::deschedule();
+        }
+    }
+
+    /// Finishes a read which was started with `read_begin`.
+    ///
+    /// Returns `true` if a writer may have modified the data during the read,
+    /// in which case whatever was read must be discarded and the read retried.
+    pub fn read_retry(&'static self, seq: uint) -> bool {
+        // Keep the reads of the data from being moved past the check.
+        atomic::fence(atomic::Acquire);
+        self.seq.load(atomic::SeqCst) as uint != seq
+    }
+
+    /// Locks this sequence lock for writing, blocking the current thread until
+    /// any other writer has finished.
+    ///
+    /// See `SeqLock::write`.
+    pub fn write(&'static self) -> StaticSeqLockWriteGuard {
+        trace::begin("SeqLock::write", self);
+        unsafe { self.lock.lock() }
+        trace::end("SeqLock::write", self);
+        StaticSeqLockWriteGuard::new(self)
+    }
+
+    /// Attempts to lock this sequence lock for writing.
+    ///
+    /// See `SeqLock::try_write`.
+    pub fn try_write(&'static self) -> Option<StaticSeqLockWriteGuard> {
+        if !faults::try_fail() && unsafe { self.lock.try_lock() } {
+            Some(StaticSeqLockWriteGuard::new(self))
+        } else {
+            None
+        }
+    }
+
+    /// Deallocate all resources associated with this static lock.
+    ///
+    /// This method is unsafe to call as there is no guarantee that there are no
+    /// active users of the lock, and this also doesn't prevent any future users
+    /// of this lock. This method is required to be called to not leak memory on
+    /// all platforms.
+    pub unsafe fn destroy(&'static self) {
+        self.lock.destroy()
+    }
+}
+
+impl StaticSeqLockWriteGuard {
+    fn new(lock: &'static StaticSeqLock) -> StaticSeqLockWriteGuard {
+        trace::acquire("SeqLock", lock);
+        let guard = StaticSeqLockWriteGuard {
+            lock: lock,
+            marker: marker::NoSend,
+            poison: lock.poison.borrow(),
+        };
+        // A poisoned lock is still marked as being written to, so readers are
+        // held off until the panic below releases the lock.
+        lock.seq.fetch_add(1, atomic::SeqCst);
+        atomic::fence(atomic::Release);
+        guard.poison.check("seqlock");
+        guard
+    }
+}
+
+impl<'a, T> Deref<T> for SeqLockWriteGuard<'a, T> {
+    fn deref(&self) -> &T { unsafe { &*self.__lock.data.get() } }
+}
+impl<'a, T> DerefMut<T> for SeqLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T { unsafe { &mut *self.__lock.data.get() } }
+}
+
+#[unsafe_destructor]
+impl Drop for StaticSeqLockWriteGuard {
+    fn drop(&mut self) {
+        self.poison.done();
+        // Publishes the write, letting readers which started since the guard
+        // was created retry.
+        self.lock.seq.fetch_add(1, atomic::SeqCst);
+        trace::release("SeqLock", self.lock);
+        unsafe { self.lock.lock.unlock() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::task;
+    use super::{SeqLock, StaticSeqLock, SEQLOCK_INIT};
+
+    #[test]
+    fn smoke() {
+        let l = SeqLock::new(1i);
+        assert_eq!(l.read(), 1);
+        *l.write() = 2;
+        assert_eq!(l.read(), 2);
+        drop(l.try_write().unwrap());
+    }
+
+    #[test]
+    fn static_smoke() {
+        static L: StaticSeqLock = SEQLOCK_INIT;
+        let seq = L.read_begin();
+        assert!(!L.read_retry(seq));
+        drop(L.write());
+        assert!(L.read_retry(seq));
+        assert!(!L.read_retry(L.read_begin()));
+        unsafe { L.destroy(); }
+    }
+
+    #[test]
+    fn try_write() {
+        let l = Arc::new(SeqLock::new(()));
+        let _g = l.write();
+        let l2 = l.clone();
+        let res = task::try(proc() { l2.try_write().is_none() });
+        assert!(res.unwrap());
+    }
+
+    #[test]
+    fn no_torn_reads() {
+        static N: uint = 10000;
+        let l = Arc::new(SeqLock::new((0u, 0u)));
+        let l2 = l.clone();
+        let (tx, rx) = channel();
+        spawn(proc() {
+            for i in range(1, N + 1) {
+                let mut g = l2.write();
+                g.0 = i;
+                g.1 = i;
+            }
+            tx.send(());
+        });
+        loop {
+            let (a, b) = l.read();
+            assert_eq!(a, b);
+            if a == N { break }
+        }
+        rx.recv();
+    }
+
+    #[test]
+    #[should_fail]
+    fn poison() {
+        let l = Arc::new(SeqLock::new(1i));
+        let l2 = l.clone();
+        let _ = task::try(proc() {
+            let _g = l2.write();
+            panic!();
+        });
+        l.read();
+    }
+
+    #[test]
+    fn poison_concurrent_readers() {
+        let l = Arc::new(SeqLock::new(1i));
+        let l2 = l.clone();
+        let (tx, rx) = channel();
+        let (tx2, rx2) = channel::<()>();
+        let writer = task::try_future(proc() {
+            let _g = l2.write();
+            tx.send(());
+            rx2.recv();
+            panic!();
+        });
+        rx.recv();
+        // these readers start while the write is in progress, and wait for it
+        let mut children = Vec::new();
+        for _ in range(0u, 4) {
+            let l2 = l.clone();
+            children.push(task::try_future(proc() { l2.read(); }));
+        }
+        tx2.send(());
+        assert!(writer.unwrap().is_err());
+        for r in children.iter_mut() {
+            assert!(r.get_ref().is_err());
+        }
+    }
+}
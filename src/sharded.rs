@@ -0,0 +1,623 @@
+use std::cell::UnsafeCell;
+use std::kinds::marker;
+use std::os;
+use std::sync::atomic::{AtomicUint, INIT_ATOMIC_UINT, Relaxed};
+use std::time::Duration;
+
+use {sys, clock, poison, faults, trace, RawLock, Instant};
+use sys::atomic;
+
+/// A reader-writer lock for data which is read far more often than it is
+/// written.
+///
+/// An `RWLock` keeps track of its readers in a single place in memory, which
+/// every reader has to modify. When many cores read the lock at once, that
+/// cache line is passed between all of them and reading stops scaling. A
+/// `ShardedRWLock` instead consists of a number of independent shards. Each
+/// reader only locks the shard of the CPU it is running on, while a writer has
+/// to acquire every shard. This makes reading cheap and scalable at the
+/// expense of making writing considerably more expensive.
+///
+/// The current CPU is known on Linux and Windows. On other platforms each
+/// thread is instead assigned a shard of its own the first time it reads a
+/// sharded lock, which only scales as long as there are no more reading
+/// threads than shards.
+///
+/// Apart from this, a `ShardedRWLock` behaves like an `RWLock` with
+/// `Policy::System`, including being poisoned if a writer panics. It doesn't
+/// support upgradable reads or downgrading, as the shards are system rwlocks
+/// which can't hand off access without letting a writer in between. There is
+/// also no static variant, as the number of shards is only known at runtime;
+/// a `Lazy` can be used instead.
+///
+/// # Example
+///
+/// ```
+/// use sync::ShardedRWLock;
+///
+/// let lock = ShardedRWLock::new(5i);
+///
+/// // many reader locks can be held at once
+/// {
+///     let r1 = lock.read();
+///     let r2 = lock.read();
+///     assert_eq!(*r1, 5);
+///     assert_eq!(*r2, 5);
+/// } // read locks are dropped at this point
+///
+/// // only one write lock may be held, however
+/// {
+///     let mut w = lock.write();
+///     *w += 1;
+///     assert_eq!(*w, 6);
+/// } // write lock is dropped here
+/// ```
+pub struct ShardedRWLock<T> {
+    shards: Vec<Shard>,
+    poison: poison::Flag,
+    poison_readers: atomic::AtomicBool,
+    data: UnsafeCell<T>,
+}
+
+/// RAII structure used to release the shared read access of a lock when
+/// dropped.
+#[must_use]
+pub struct ShardedRWLockReadGuard<'a, T: 'a> {
+    lock: &'a ShardedRWLock<T>,
+    shard: &'a sys::RWLock,
+    marker: marker::NoSend,
+    poison: Option<poison::Guard<'a>>,
+}
+
+/// RAII structure used to release the exclusive write access of a lock when
+/// dropped.
+#[must_use]
+pub struct ShardedRWLockWriteGuard<'a, T: 'a> {
+    lock: &'a ShardedRWLock<T>,
+    marker: marker::NoSend,
+    poison: poison::Guard<'a>,
+}
+
+// Each shard is padded out to more than a cache line so that readers using
+// neighbouring shards don't contend on the same line.
+struct Shard {
+    lock: sys::RWLock,
+    _pad: [u64, ..CACHE_LINE / 8],
+}
+
+pub const CACHE_LINE: uint = 64;
+
+// Where the current CPU isn't known, threads are assigned shards round-robin
+// the first time they read any sharded lock.
+static NEXT_SHARD: AtomicUint = INIT_ATOMIC_UINT;
+
+thread_local!(static SHARD: uint = NEXT_SHARD.fetch_add(1, Relaxed))
+
+impl<T: Send + Sync> ShardedRWLock<T> {
+    /// Creates a new sharded lock with one shard for each CPU of the system.
+    pub fn new(t: T) -> ShardedRWLock<T> {
+        ShardedRWLock::with_shards(t, os::num_cpus())
+    }
+
+    /// Creates a new sharded lock with the specified number of shards.
+    ///
+    /// With fewer shards than CPUs, readers on some CPUs will share a shard.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if `shards` is zero.
+    pub fn with_shards(t: T, shards: uint) -> ShardedRWLock<T> {
+        assert!(shards > 0, "a sharded lock needs at least one shard");
+        // The shards are never moved once this vector is built, as it's never
+        // grown.
+        let shards = Vec::from_fn(shards, |_| Shard {
+            lock: unsafe { sys::RWLock::new() },
+            _pad: [0, ..CACHE_LINE / 8],
+        });
+        ShardedRWLock {
+            shards: shards,
+            poison: poison::FLAG_INIT,
+            poison_readers: atomic::INIT_ATOMIC_BOOL,
+            data: UnsafeCell::new(t),
+        }
+    }
+
+    /// Returns the number of shards of this lock.
+    pub fn shards(&self) -> uint { self.shards.len() }
+
+    /// Sets whether a panic while holding shared read access poisons this
+    /// lock.
+    ///
+    /// See `RWLock::set_poison_readers`.
+    pub fn set_poison_readers(&self, poison: bool) {
+        self.poison_readers.store(poison, atomic::SeqCst)
+    }
+
+    /// Returns whether a panic while holding shared read access poisons this
+    /// lock.
+    pub fn poisons_readers(&self) -> bool {
+        self.poison_readers.load(atomic::SeqCst)
+    }
+
+    /// Locks this lock with shared read access, blocking the current thread
+    /// until it can be acquired.
+    ///
+    /// Only the shard of the calling thread's CPU is locked. See
+    /// `RWLock::read`.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the lock is poisoned. The panic will occur
+    /// immediately after the lock has been acquired.
+    pub fn read(&self) -> ShardedRWLockReadGuard<T> {
+        self.read_shard(self.shard())
+    }
+
+    /// Attempt to acquire this lock with shared read access.
+    ///
+    /// Returns `None` if a call to `read` would otherwise block. See
+    /// `RWLock::try_read`.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the lock is poisoned. A panic will only
+    /// occur if the lock is acquired.
+    pub fn try_read(&self) -> Option<ShardedRWLockReadGuard<T>> {
+        self.try_read_shard(self.shard())
+    }
+
+    /// Locks this lock with shared read access, blocking the current thread
+    /// for at most `dur`.
+    ///
+    /// See `RWLock::read_timeout`.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the lock is poisoned. A panic will only
+    /// occur if the lock is acquired.
+    pub fn read_timeout(&self, dur: Duration)
+                        -> Option<ShardedRWLockReadGuard<T>> {
+        self.read_deadline(clock::now() + dur)
+    }
+
+    /// Locks this lock with shared read access, blocking the current thread
+    /// until at most `deadline`.
+    ///
+    /// See `RWLock::read_deadline`.
+    pub fn read_deadline(&self, deadline: Instant)
+                         -> Option<ShardedRWLockReadGuard<T>> {
+        if faults::timeout() { return None }
+        let shard = &self.shards[self.shard()].lock;
+        trace::begin("ShardedRWLock::read_deadline", self);
+        let acquired = unsafe { lock_deadline(shard, deadline, false) };
+        trace::end("ShardedRWLock::read_deadline", self);
+        if acquired {
+            Some(ShardedRWLockReadGuard::new(self, shard))
+        } else {
+            None
+        }
+    }
+
+    /// Lock this lock with exclusive write access, blocking the current thread
+    /// until it can be acquired.
+    ///
+    /// Every shard of the lock is acquired, so this is considerably more
+    /// expensive than `RWLock::write`.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the lock is poisoned. The panic will occur
+    /// when the lock is acquired.
+    pub fn write(&self) -> ShardedRWLockWriteGuard<T> {
+        trace::begin("ShardedRWLock::write", self);
+        unsafe { self.write_all() }
+        trace::end("ShardedRWLock::write", self);
+        ShardedRWLockWriteGuard::new(self)
+    }
+
+    /// Attempt to lock this lock with exclusive write access.
+    ///
+    /// Returns `None` if a call to `write` would otherwise block. See
+    /// `RWLock::try_write`.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the lock is poisoned. A panic will only
+    /// occur if the lock is acquired.
+    pub fn try_write(&self) -> Option<ShardedRWLockWriteGuard<T>> {
+        if faults::try_fail() { return None }
+        if unsafe { self.write_all_with(|shard| shard.try_write()) } {
+            Some(ShardedRWLockWriteGuard::new(self))
+        } else {
+            None
+        }
+    }
+
+    /// Lock this lock with exclusive write access, blocking the current thread
+    /// for at most `dur`.
+    ///
+    /// See `RWLock::write_timeout`.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the lock is poisoned. A panic will only
+    /// occur if the lock is acquired.
+    pub fn write_timeout(&self, dur: Duration)
+                         -> Option<ShardedRWLockWriteGuard<T>> {
+        self.write_deadline(clock::now() + dur)
+    }
+
+    /// Lock this lock with exclusive write access, blocking the current thread
+    /// until at most `deadline`.
+    ///
+    /// The deadline applies to acquiring all of the shards together. See
+    /// `RWLock::write_deadline`.
+    pub fn write_deadline(&self, deadline: Instant)
+                          -> Option<ShardedRWLockWriteGuard<T>> {
+        if faults::timeout() { return None }
+        trace::begin("ShardedRWLock::write_deadline", self);
+        let acquired = unsafe {
+            self.write_all_with(|shard| lock_deadline(shard, deadline, true))
+        };
+        trace::end("ShardedRWLock::write_deadline", self);
+        if acquired {
+            Some(ShardedRWLockWriteGuard::new(self))
+        } else {
+            None
+        }
+    }
+
+    fn read_shard(&self, i: uint) -> ShardedRWLockReadGuard<T> {
+        let shard = &self.shards[i].lock;
+        trace::begin("ShardedRWLock::read", self);
+        unsafe { shard.read() }
+        trace::end("ShardedRWLock::read", self);
+        ShardedRWLockReadGuard::new(self, shard)
+    }
+
+    fn try_read_shard(&self, i: uint) -> Option<ShardedRWLockReadGuard<T>> {
+        let shard = &self.shards[i].lock;
+        if !faults::try_fail() && unsafe { shard.try_read() } {
+            Some(ShardedRWLockReadGuard::new(self, shard))
+        } else {
+            None
+        }
+    }
+
+    // The index of the shard which the calling thread reads through.
+    fn shard(&self) -> uint { current() % self.shards.len() }
+}
+
+impl<T> ShardedRWLock<T> {
+    unsafe fn write_all(&self) {
+        self.write_all_with(|shard| { shard.write(); true });
+    }
+
+    // Acquires write access to every shard with `lock`, releasing the shards
+    // acquired so far if it fails. Writers always acquire the shards in the
+    // same order, so they can't deadlock with one another.
+    unsafe fn write_all_with(&self, lock: |&sys::RWLock| -> bool) -> bool {
+        for (i, shard) in self.shards.iter().enumerate() {
+            if !lock(&shard.lock) {
+                for shard in self.shards.slice_to(i).iter().rev() {
+                    shard.lock.write_unlock();
+                }
+                return false
+            }
+        }
+        true
+    }
+
+    unsafe fn write_unlock_all(&self) {
+        for shard in self.shards.iter().rev() {
+            shard.lock.write_unlock();
+        }
+    }
+}
+
+// Returns the calling thread's CPU, or where that isn't known a number which
+// was assigned to the thread round-robin, for spreading threads over shards.
+pub fn current() -> uint {
+    match sys::cpu::current() {
+        Some(cpu) => cpu,
+        None => SHARD.with(|s| *s),
+    }
+}
+
+// Acquires `shard` for reading or writing before the calling thread's clock
+// reaches `deadline`.
+unsafe fn lock_deadline(shard: &sys::RWLock, deadline: Instant, write: bool)
+                        -> bool {
+    if clock::is_system() {
+        let dur = deadline.duration_since(clock::now());
+        if write { shard.write_timeout(dur) } else { shard.read_timeout(dur) }
+    } else if write {
+        clock::poll_until(deadline, || shard.try_write())
+    } else {
+        clock::poll_until(deadline, || shard.try_read())
+    }
+}
+
+#[unsafe_destructor]
+impl<T> Drop for ShardedRWLock<T> {
+    fn drop(&mut self) {
+        for shard in self.shards.iter() {
+            unsafe { shard.lock.destroy() }
+        }
+    }
+}
+
+impl<'a, T> ShardedRWLockReadGuard<'a, T> {
+    fn new(lock: &'a ShardedRWLock<T>, shard: &'a sys::RWLock)
+           -> ShardedRWLockReadGuard<'a, T> {
+        trace::acquire("ShardedRWLock (read)", lock);
+        // Readers only hold on to the poison flag if they are able to poison
+        // it.
+        let poison = if lock.poisons_readers() {
+            Some(lock.poison.borrow())
+        } else {
+            None
+        };
+        let guard = ShardedRWLockReadGuard {
+            lock: lock,
+            shard: shard,
+            marker: marker::NoSend,
+            poison: poison,
+        };
+        lock.poison.borrow().check("rwlock");
+        return guard;
+    }
+}
+
+impl<'a, T> ShardedRWLockWriteGuard<'a, T> {
+    fn new(lock: &'a ShardedRWLock<T>) -> ShardedRWLockWriteGuard<'a, T> {
+        trace::acquire("ShardedRWLock (write)", lock);
+        let guard = ShardedRWLockWriteGuard {
+            lock: lock,
+            marker: marker::NoSend,
+            poison: lock.poison.borrow(),
+        };
+        guard.poison.check("rwlock");
+        guard
+    }
+}
+
+impl<'a, T> Deref<T> for ShardedRWLockReadGuard<'a, T> {
+    fn deref(&self) -> &T { unsafe { &*self.lock.data.get() } }
+}
+impl<'a, T> Deref<T> for ShardedRWLockWriteGuard<'a, T> {
+    fn deref(&self) -> &T { unsafe { &*self.lock.data.get() } }
+}
+impl<'a, T> DerefMut<T> for ShardedRWLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T { unsafe { &mut *self.lock.data.get() } }
+}
+
+impl<'a, T> RawLock for ShardedRWLockReadGuard<'a, T> {
+    unsafe fn unlock(&self) {
+        trace::release("ShardedRWLock (read)", self.lock);
+        self.shard.read_unlock();
+    }
+
+    unsafe fn relock(&self) {
+        self.shard.read();
+        trace::acquire("ShardedRWLock (read)", self.lock);
+        self.lock.poison.borrow().check("rwlock");
+    }
+}
+
+impl<'a, T> RawLock for ShardedRWLockWriteGuard<'a, T> {
+    unsafe fn unlock(&self) {
+        trace::release("ShardedRWLock (write)", self.lock);
+        self.lock.write_unlock_all();
+    }
+
+    unsafe fn relock(&self) {
+        self.lock.write_all();
+        trace::acquire("ShardedRWLock (write)", self.lock);
+        self.poison.check("rwlock");
+    }
+}
+
+#[unsafe_destructor]
+impl<'a, T> Drop for ShardedRWLockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        match self.poison {
+            Some(ref mut poison) => poison.done(),
+            None => {}
+        }
+        trace::release("ShardedRWLock (read)", self.lock);
+        unsafe { self.shard.read_unlock(); }
+    }
+}
+
+#[unsafe_destructor]
+impl<'a, T> Drop for ShardedRWLockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        self.poison.done();
+        trace::release("ShardedRWLock (write)", self.lock);
+        unsafe { self.lock.write_unlock_all(); }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::timer;
+    use std::sync::Arc;
+    use std::task;
+    use std::time::Duration;
+    use super::ShardedRWLock;
+    use AnyCondvar;
+
+    #[test]
+    fn smoke() {
+        let l = ShardedRWLock::new(());
+        assert!(l.shards() > 0);
+        drop(l.read());
+        drop(l.write());
+        drop((l.read(), l.read()));
+        drop(l.write());
+    }
+
+    #[test]
+    fn shard_in_range() {
+        let l = Arc::new(ShardedRWLock::with_shards((), 3));
+        let mut children = Vec::new();
+        for _ in range(0u, 8) {
+            let l2 = l.clone();
+            children.push(task::try_future(proc() { l2.shard() < 3 }));
+        }
+        for r in children.into_iter() {
+            assert!(r.unwrap().unwrap());
+        }
+    }
+
+    #[test]
+    fn writer_excludes_every_shard() {
+        let l = ShardedRWLock::with_shards(0i, 4);
+        {
+            let _w = l.write();
+            for i in range(0u, 4) {
+                assert!(l.try_read_shard(i).is_none());
+            }
+        }
+        // readers on different shards don't exclude each other
+        let rs = Vec::from_fn(4, |i| l.try_read_shard(i).unwrap());
+        assert!(l.try_write().is_none());
+        drop(rs);
+    }
+
+    #[test]
+    fn reader_on_any_shard_excludes_writers() {
+        let l = ShardedRWLock::with_shards(0i, 4);
+        for i in range(0u, 4) {
+            let r = l.read_shard(i);
+            assert!(l.try_write().is_none());
+            // the shards acquired by the failed attempt were released again
+            for j in range(0u, 4).filter(|&j| j != i) {
+                drop(l.try_read_shard(j).unwrap());
+            }
+            drop(r);
+        }
+        drop(l.try_write().unwrap());
+    }
+
+    #[test]
+    fn writer_waits_for_last_shard() {
+        let l = Arc::new(ShardedRWLock::with_shards(0i, 4));
+        let r = l.read_shard(3);
+        let l2 = l.clone();
+        let res = task::try(proc() {
+            l2.write_timeout(Duration::milliseconds(10)).is_none()
+        });
+        assert!(res.unwrap());
+        for i in range(0u, 3) {
+            drop(l.try_read_shard(i).unwrap());
+        }
+
+        let l2 = l.clone();
+        let (tx, rx) = channel();
+        spawn(proc() {
+            *l2.write() += 1;
+            tx.send(());
+        });
+        timer::sleep(Duration::milliseconds(10));
+        assert_eq!(*r, 0);
+        drop(r);
+        rx.recv();
+        assert_eq!(*l.read(), 1);
+    }
+
+    #[test]
+    fn timeouts() {
+        let dur = Duration::milliseconds(1);
+        let l = Arc::new(ShardedRWLock::with_shards((), 4));
+        let l2 = l.clone();
+        let g = l.write();
+        let res = task::try(proc() {
+            l2.read_timeout(dur).is_none() && l2.write_timeout(dur).is_none()
+        });
+        assert!(res.unwrap());
+        drop(g);
+        drop(l.read_timeout(dur).unwrap());
+        drop(l.write_timeout(dur).unwrap());
+    }
+
+    #[test]
+    fn frob() {
+        static N: uint = 10;
+        static M: uint = 1000;
+
+        let l = Arc::new(ShardedRWLock::with_shards(0u, 3));
+        let (tx, rx) = channel::<()>();
+        for i in range(0, N) {
+            let tx = tx.clone();
+            let l = l.clone();
+            spawn(proc() {
+                for _ in range(0, M) {
+                    if i % 2 == 0 {
+                        *l.write() += 1;
+                    } else {
+                        let before = *l.read();
+                        assert!(before <= N / 2 * M);
+                    }
+                }
+                drop(tx);
+            });
+        }
+        drop(tx);
+        let _ = rx.recv_opt();
+        assert_eq!(*l.read(), N / 2 * M);
+    }
+
+    #[test]
+    fn any_condvar() {
+        let pair = Arc::new((ShardedRWLock::new(false), AnyCondvar::new()));
+        let pair2 = pair.clone();
+        spawn(proc() {
+            let &(ref lock, ref cvar) = &*pair2;
+            *lock.write() = true;
+            cvar.notify_all();
+        });
+
+        let &(ref lock, ref cvar) = &*pair;
+        let started = lock.read();
+        while !*started {
+            cvar.wait(&started);
+        }
+    }
+
+    #[test]
+    #[should_fail]
+    fn poison_wr() {
+        let arc = Arc::new(ShardedRWLock::with_shards(1i, 4));
+        let arc2 = arc.clone();
+        let _ = task::try(proc() {
+            let lock = arc2.write();
+            assert_eq!(*lock, 2);
+        });
+        // the writer held every shard, so a reader on any one sees the poison
+        let lock = arc.read_shard(3);
+        assert_eq!(*lock, 1);
+    }
+
+    #[test]
+    fn poison_readers() {
+        let arc = Arc::new(ShardedRWLock::with_shards(1i, 4));
+        let arc2 = arc.clone();
+        let _ = task::try(proc() {
+            let _r = arc2.read_shard(1);
+            panic!();
+        });
+        drop(arc.write());
+
+        arc.set_poison_readers(true);
+        let arc2 = arc.clone();
+        let _ = task::try(proc() {
+            let _r = arc2.read_shard(1);
+            panic!();
+        });
+        let arc2 = arc.clone();
+        let res = task::try(proc() { drop(arc2.read_shard(0)); });
+        assert!(res.is_err());
+    }
+}
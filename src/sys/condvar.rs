@@ -21,6 +21,17 @@ impl Condvar {
     #[inline]
     pub unsafe fn new() -> Condvar { Condvar(imp::Condvar::new()) }
 
+    /// Prepares this condition variable to measure timeouts against the
+    /// system's monotonic clock.
+    ///
+    /// This may only be called after the condition variable has reached its
+    /// final location in memory. Condition variables which are never
+    /// explicitly initialized, such as those created with `CONDVAR_INIT`, are
+    /// prepared in the same way the first time they are used, so calling this
+    /// only moves that work out of the first wait or notification.
+    #[inline]
+    pub unsafe fn init(&mut self) { self.0.init() }
+
     /// Signal one waiter on this condition variable to wake up.
     #[inline]
     pub unsafe fn notify_one(&self) { self.0.notify_one() }
@@ -40,6 +51,9 @@ impl Condvar {
     /// Wait for a signal on the specified mutex with a timeout duration
     /// specified by `dur` (a relative time into the future).
     ///
+    /// Durations too large to be represented by the system are treated as an
+    /// effectively infinite timeout.
+    ///
     /// Behavior is undefined if the mutex is not locked by the current thread.
     /// Behavior is also undefined if more than one mutex is used concurrently
     /// on this condition variable.
@@ -48,6 +62,21 @@ impl Condvar {
         self.0.wait_timeout(mutex::raw(mutex), dur)
     }
 
+    /// Wait for a signal on the specified mutex until the system's monotonic
+    /// clock, as returned by `sys::time::monotonic`, reaches `deadline`.
+    ///
+    /// Returns `false` if the deadline was reached, and `true` otherwise.
+    /// Deadlines in the past time out immediately.
+    ///
+    /// Behavior is undefined if the mutex is not locked by the current thread.
+    /// Behavior is also undefined if more than one mutex is used concurrently
+    /// on this condition variable.
+    #[inline]
+    pub unsafe fn wait_deadline(&self, mutex: &Mutex,
+                                deadline: Duration) -> bool {
+        self.0.wait_deadline(mutex::raw(mutex), deadline)
+    }
+
     /// Deallocate all resources associated with this condition variable.
     ///
     /// Behavior is undefined if there are current or will be future users of
@@ -56,18 +85,34 @@ impl Condvar {
     pub unsafe fn destroy(&self) { self.0.destroy() }
 }
 
-#[cfg(unix)]
+#[cfg(all(unix, not(feature = "model")))]
 mod imp {
     use std::cell::UnsafeCell;
+    use std::cmp;
+    use std::mem;
+    use std::sync::atomic::{mod, AtomicUint};
+    use std::task;
     use std::time::Duration;
     use libc;
 
-    use sys::ffi;
+    use sys::{ffi, time};
 
-    pub struct Condvar { inner: UnsafeCell<ffi::pthread_cond_t> }
+    pub struct Condvar {
+        inner: UnsafeCell<ffi::pthread_cond_t>,
+        state: AtomicUint,
+    }
+
+    // Values of `Condvar::state`. A condvar is set up to use the monotonic
+    // clock before its first use, falling back to the real-time clock if the
+    // platform doesn't support that.
+    const UNINIT: uint = 0;
+    const INITIALIZING: uint = 1;
+    const MONOTONIC: uint = 2;
+    const REALTIME: uint = 3;
 
     pub const CONDVAR_INIT: Condvar = Condvar {
         inner: UnsafeCell { value: ffi::PTHREAD_COND_INITIALIZER },
+        state: atomic::INIT_ATOMIC_UINT,
     };
 
     impl Condvar {
@@ -75,23 +120,29 @@ mod imp {
         pub unsafe fn new() -> Condvar {
             // Might be moved and address is changing it is better to avoid
             // initialization of potentially opaque OS data before it landed
-            Condvar { inner: UnsafeCell::new(ffi::PTHREAD_COND_INITIALIZER) }
+            CONDVAR_INIT
         }
 
+        #[inline]
+        pub unsafe fn init(&mut self) { self.ensure_init() }
+
         #[inline]
         pub unsafe fn notify_one(&self) {
+            self.ensure_init();
             let r = ffi::pthread_cond_signal(self.inner.get());
             debug_assert_eq!(r, 0);
         }
 
         #[inline]
         pub unsafe fn notify_all(&self) {
+            self.ensure_init();
             let r = ffi::pthread_cond_broadcast(self.inner.get());
             debug_assert_eq!(r, 0);
         }
 
         #[inline]
         pub unsafe fn wait(&self, mutex: *mut ffi::pthread_mutex_t) {
+            self.ensure_init();
             let r = ffi::pthread_cond_wait(self.inner.get(), mutex);
             debug_assert_eq!(r, 0);
         }
@@ -99,31 +150,56 @@ mod imp {
         pub unsafe fn wait_timeout(&self, mutex: *mut ffi::pthread_mutex_t,
                                    dur: Duration) -> bool {
             assert!(dur >= Duration::nanoseconds(0));
+            self.ensure_init();
+            notified(self.timedwait(mutex, dur))
+        }
 
-            // First, figure out what time it currently is
-            let mut tv = libc::timeval { tv_sec: 0, tv_usec: 0 };
-            let r = ffi::gettimeofday(&mut tv, 0 as *mut _);
-            debug_assert_eq!(r, 0);
-
-            // Offset that time with the specified duration
-            let abs = Duration::seconds(tv.tv_sec as i64) +
-                      Duration::microseconds(tv.tv_usec as i64) +
-                      dur;
-            let ns = abs.num_nanoseconds().unwrap() as u64;
-            let timeout = libc::timespec {
-                tv_sec: (ns / 1000000000) as libc::time_t,
-                tv_nsec: (ns % 1000000000) as libc::c_long,
-            };
+        pub unsafe fn wait_deadline(&self, mutex: *mut ffi::pthread_mutex_t,
+                                    deadline: Duration) -> bool {
+            let deadline = cmp::max(deadline, Duration::zero());
+            self.ensure_init();
+            // A condvar using the monotonic clock can be handed the deadline
+            // directly, otherwise it has to be made relative to the clock
+            // which the condvar is actually using.
+            if !self.monotonic() {
+                let dur = deadline - time::monotonic();
+                return self.wait_timeout(mutex, cmp::max(dur, Duration::zero()))
+            }
+            let zero = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+            let timeout = time::timespec_add(zero, deadline);
+            notified(ffi::pthread_cond_timedwait(self.inner.get(), mutex,
+                                                 &timeout))
+        }
 
-            // And wait!
-            let r = ffi::pthread_cond_timedwait(self.inner.get(), mutex,
-                                                &timeout);
-            if r != 0 {
-                debug_assert_eq!(r as int, libc::ETIMEDOUT as int);
-                false
+        #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+        unsafe fn timedwait(&self, mutex: *mut ffi::pthread_mutex_t,
+                            dur: Duration) -> libc::c_int {
+            // First, figure out what time it currently is on the clock that
+            // this condvar was initialized with
+            let mut now = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+            if self.monotonic() {
+                let r = ffi::clock_gettime(ffi::CLOCK_MONOTONIC, &mut now);
+                debug_assert_eq!(r, 0);
             } else {
-                true
+                let mut tv = libc::timeval { tv_sec: 0, tv_usec: 0 };
+                let r = ffi::gettimeofday(&mut tv, 0 as *mut _);
+                debug_assert_eq!(r, 0);
+                now.tv_sec = tv.tv_sec;
+                now.tv_nsec = tv.tv_usec as libc::c_long * 1000;
             }
+
+            // Offset that time with the specified duration, and wait!
+            let timeout = time::timespec_add(now, dur);
+            ffi::pthread_cond_timedwait(self.inner.get(), mutex, &timeout)
+        }
+
+        #[cfg(any(target_os = "macos", target_os = "ios"))]
+        unsafe fn timedwait(&self, mutex: *mut ffi::pthread_mutex_t,
+                            dur: Duration) -> libc::c_int {
+            let zero = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+            let timeout = time::timespec_add(zero, dur);
+            ffi::pthread_cond_timedwait_relative_np(self.inner.get(), mutex,
+                                                    &timeout)
         }
 
         #[inline]
@@ -131,18 +207,81 @@ mod imp {
             let r = ffi::pthread_cond_destroy(self.inner.get());
             debug_assert_eq!(r, 0);
         }
+
+        #[inline]
+        fn monotonic(&self) -> bool {
+            self.state.load(atomic::SeqCst) == MONOTONIC
+        }
+
+        // Every operation calls this before touching the pthread condvar, so
+        // statically initialized condvars are re-initialized before anyone
+        // could be using them.
+        #[inline]
+        unsafe fn ensure_init(&self) {
+            if self.state.load(atomic::SeqCst) < MONOTONIC {
+                self.init_slow()
+            }
+        }
+
+        #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+        #[inline(never)]
+        unsafe fn init_slow(&self) {
+            if self.state.compare_and_swap(UNINIT, INITIALIZING,
+                                           atomic::SeqCst) != UNINIT {
+                while self.state.load(atomic::SeqCst) == INITIALIZING {
+                    task::deschedule();
+                }
+                return
+            }
+            let mut attr: ffi::pthread_condattr_t = mem::zeroed();
+            let r = ffi::pthread_condattr_init(&mut attr);
+            debug_assert_eq!(r, 0);
+            let r = ffi::pthread_condattr_setclock(&mut attr,
+                                                   ffi::CLOCK_MONOTONIC);
+            let state = if r == 0 {
+                let r = ffi::pthread_cond_init(self.inner.get(), &attr);
+                debug_assert_eq!(r, 0);
+                MONOTONIC
+            } else {
+                REALTIME
+            };
+            let r = ffi::pthread_condattr_destroy(&mut attr);
+            debug_assert_eq!(r, 0);
+            self.state.store(state, atomic::SeqCst);
+        }
+
+        // Timed waits on OSX are always relative, so there's nothing to do.
+        #[cfg(any(target_os = "macos", target_os = "ios"))]
+        unsafe fn init_slow(&self) {
+            self.state.store(REALTIME, atomic::SeqCst);
+        }
+    }
+
+    fn notified(r: libc::c_int) -> bool {
+        if r != 0 {
+            debug_assert_eq!(r as int, libc::ETIMEDOUT as int);
+            false
+        } else {
+            true
+        }
     }
 }
 
-#[cfg(windows)]
+#[cfg(feature = "model")]
+mod imp {
+    pub use sys::model::{Condvar, CONDVAR_INIT};
+}
+
+#[cfg(all(windows, not(feature = "model")))]
 mod imp {
     use std::cell::UnsafeCell;
+    use std::cmp;
     use std::os;
     use std::time::Duration;
 
     use libc::DWORD;
     use libc;
-    use sys::ffi;
+    use sys::{ffi, time};
 
     pub struct Condvar { inner: UnsafeCell<ffi::CONDITION_VARIABLE> }
 
@@ -154,6 +293,11 @@ mod imp {
         #[inline]
         pub unsafe fn new() -> Condvar { CONDVAR_INIT }
 
+        // Condition variables on windows always measure timeouts against a
+        // clock which is unaffected by changes to the system time.
+        #[inline]
+        pub unsafe fn init(&mut self) {}
+
         #[inline]
         pub unsafe fn wait(&self, mutex: ffi::LPCRITICAL_SECTION) {
             let r = ffi::SleepConditionVariableCS(self.inner.get(),
@@ -164,9 +308,15 @@ mod imp {
 
         pub unsafe fn wait_timeout(&self, mutex: ffi::LPCRITICAL_SECTION,
                                    dur: Duration) -> bool {
-            let r = ffi::SleepConditionVariableCS(self.inner.get(),
-                                                  mutex,
-                                                  dur.num_milliseconds() as DWORD);
+            // INFINITE is a special value, so anything longer is clamped to
+            // the longest finite timeout.
+            let ms = dur.num_milliseconds();
+            let ms = if ms >= libc::INFINITE as i64 {
+                libc::INFINITE - 1
+            } else {
+                ms as DWORD
+            };
+            let r = ffi::SleepConditionVariableCS(self.inner.get(), mutex, ms);
             if r == 0 {
                 const ERROR_TIMEOUT: DWORD = 0x5B4;
                 debug_assert_eq!(os::errno() as uint, ERROR_TIMEOUT as uint);
@@ -176,6 +326,12 @@ mod imp {
             }
         }
 
+        pub unsafe fn wait_deadline(&self, mutex: ffi::LPCRITICAL_SECTION,
+                                    deadline: Duration) -> bool {
+            let dur = deadline - time::monotonic();
+            self.wait_timeout(mutex, cmp::max(dur, Duration::zero()))
+        }
+
         #[inline]
         pub unsafe fn notify_one(&self) {
             ffi::WakeConditionVariable(self.inner.get())
@@ -0,0 +1,36 @@
+//! Information about the processors of the system
+
+/// Returns the index of the processor which the calling thread is currently
+/// running on, or `None` if the platform can't tell.
+///
+/// The thread may be moved to another processor at any time, so the result is
+/// only a hint.
+pub fn current() -> Option<uint> { imp::current() }
+
+#[cfg(all(target_os = "linux", not(feature = "model")))]
+mod imp {
+    use sys::ffi;
+
+    pub fn current() -> Option<uint> {
+        match unsafe { ffi::sched_getcpu() } {
+            -1 => None,
+            cpu => Some(cpu as uint),
+        }
+    }
+}
+
+#[cfg(all(windows, not(feature = "model")))]
+mod imp {
+    use sys::ffi;
+
+    pub fn current() -> Option<uint> {
+        Some(unsafe { ffi::GetCurrentProcessorNumber() } as uint)
+    }
+}
+
+// The model has no notion of processors, and other platforms have no cheap way
+// of finding out which one a thread is running on.
+#[cfg(any(feature = "model", not(any(target_os = "linux", windows))))]
+mod imp {
+    pub fn current() -> Option<uint> { None }
+}
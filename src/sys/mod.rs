@@ -13,6 +13,25 @@ pub use self::mutex::{Mutex, MUTEX_INIT};
 pub use self::condvar::{Condvar, CONDVAR_INIT};
 pub use self::rwlock::{RWLock, RWLOCK_INIT};
 
+pub mod time;
+pub mod cpu;
+
+#[cfg(feature = "model")] pub mod model;
+
+/// Atomic types used by the primitives built in this crate.
+///
+/// These are the atomics from the standard library unless the `model` feature
+/// is enabled, in which case they are the simulated atomics of `model`.
+#[cfg(not(feature = "model"))]
+pub mod atomic {
+    pub use std::sync::atomic::{AtomicInt, INIT_ATOMIC_INT, Ordering, SeqCst};
+    pub use std::sync::atomic::{AtomicBool, INIT_ATOMIC_BOOL};
+    pub use std::sync::atomic::{AtomicUint, INIT_ATOMIC_UINT, AtomicPtr};
+    pub use std::sync::atomic::{Acquire, Release, fence};
+}
+#[cfg(feature = "model")]
+pub use self::model::atomic;
+
 mod mutex;
 mod condvar;
 mod rwlock;
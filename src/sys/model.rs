@@ -0,0 +1,1016 @@
+//! A deterministic model-checking backend for the `sys` primitives
+//!
+//! When this crate is compiled with the `model` feature, the mutexes,
+//! condition variables, and rwlocks in `sys` (and therefore all of the safe
+//! primitives built on top of them) are replaced with simulated versions whose
+//! interleavings are controlled by the scheduler in this module. The atomics
+//! in `sys::atomic` used by `Once` are replaced as well.
+//!
+//! A test is run with `check`, which executes a closure many times. Each
+//! execution only ever allows one thread to run at a time, and every operation
+//! on a primitive is a point at which the scheduler may switch to another
+//! thread. Threads participating in an execution must be created with
+//! `spawn`. Executions either pick a random thread at each point, or, when
+//! `Builder::exhaustive` is requested, systematically enumerate every possible
+//! interleaving. Simulated condition variables may also wake up spuriously and
+//! timed waits may time out at any point.
+//!
+//! If an execution panics or deadlocks then `check` will panic with a
+//! description of the failure as well as the schedule and configuration which
+//! led to it. This schedule can be passed to `Builder::replay` on the same
+//! configuration to deterministically run the same interleaving again.
+//!
+//! Only sequentially consistent executions are explored, weaker memory
+//! orderings are not modeled.
+//!
+//! # Example
+//!
+//! ```
+//! use std::sync::Arc;
+//! use sync::Mutex;
+//! use sync::sys::model;
+//!
+//! fn body() {
+//!     let m = Arc::new(Mutex::new(0u));
+//!     let m2 = m.clone();
+//!     let t = model::spawn(proc() { *m2.lock() += 1; });
+//!     *m.lock() += 1;
+//!     t.join();
+//!     assert_eq!(*m.lock(), 2);
+//! }
+//!
+//! model::Builder::new().exhaustive(true).check(body);
+//! ```
+
+use std::any::{Any, AnyRefExt};
+use std::cell::RefCell;
+use std::cmp;
+use std::io::stdio;
+use std::rand::{Rng, SeedableRng, XorShiftRng};
+use std::sync::Mutex as StdMutex;
+use std::sync::Arc;
+use std::sync::atomic::{mod, AtomicBool, AtomicUint};
+use std::task;
+use std::time::Duration;
+use std::uint;
+
+use sys::time;
+
+/// Configuration for how executions of a test are explored.
+#[deriving(Clone, Show)]
+pub struct Builder {
+    iterations: uint,
+    seed: u64,
+    exhaustive: bool,
+    spurious_wakeups: bool,
+}
+
+/// A handle to a thread spawned inside of a model execution.
+pub struct JoinHandle {
+    id: uint,
+}
+
+struct Context {
+    shared: Arc<StdMutex<Execution>>,
+    id: uint,
+    wake: Receiver<()>,
+}
+
+struct Execution {
+    threads: Vec<Thread>,
+    live: uint,
+    schedule: Vec<uint>,
+    branches: Vec<uint>,
+    replay: Vec<uint>,
+    rng: Option<XorShiftRng>,
+    spurious_wakeups: bool,
+    failure: Option<String>,
+    aborting: bool,
+    done: Sender<()>,
+}
+
+struct Thread {
+    blocked_on: Option<uint>,
+    timed: bool,
+    timed_out: bool,
+    finished: bool,
+    wake: Sender<()>,
+}
+
+struct Outcome {
+    schedule: Vec<uint>,
+    branches: Vec<uint>,
+    failure: Option<String>,
+}
+
+thread_local!(static CURRENT: RefCell<Option<Context>> = RefCell::new(None))
+
+/// Runs `f` under a number of random interleavings with the default
+/// configuration, panicking if any of them fail.
+///
+/// See `Builder::check`.
+pub fn check(f: fn()) { Builder::new().check(f) }
+
+/// Runs `f` once with the default configuration, following the schedule
+/// specified.
+///
+/// Schedules reported by a `Builder` with a non-default configuration must be
+/// replayed with `Builder::replay` on the same configuration.
+///
+/// See `Builder::replay`.
+pub fn replay(schedule: &[uint], f: fn()) { Builder::new().replay(schedule, f) }
+
+/// Spawns a new thread which participates in the current model execution.
+///
+/// # Panics
+///
+/// This function will panic if it is not called from a thread which is part
+/// of an execution started by `check` or `replay`.
+pub fn spawn(f: proc(): Send) -> JoinHandle {
+    let shared = match context() {
+        Some((shared, _)) => shared,
+        None => panic!("model::spawn called outside of a model execution"),
+    };
+    let id = launch(shared, f);
+    yield_point();
+    JoinHandle { id: id }
+}
+
+impl JoinHandle {
+    /// Blocks the current thread until the spawned thread has finished.
+    pub fn join(self) {
+        let (shared, _) = context().expect("joined outside of an execution");
+        loop {
+            if shared.lock().threads[self.id].finished { break }
+            block(join_key(self.id), false);
+        }
+    }
+}
+
+impl Builder {
+    /// Creates a new configuration which will explore 1000 random
+    /// interleavings of a test, with spurious wakeups enabled.
+    pub fn new() -> Builder {
+        Builder {
+            iterations: 1000,
+            seed: 0,
+            exhaustive: false,
+            spurious_wakeups: true,
+        }
+    }
+
+    /// Sets the number of executions to run.
+    ///
+    /// When running exhaustively, this is an upper bound on the number of
+    /// executions explored. If it is reached before every interleaving has
+    /// been explored then `check` prints a warning saying so.
+    pub fn iterations(mut self, iterations: uint) -> Builder {
+        self.iterations = iterations;
+        self
+    }
+
+    /// Sets the seed used to pick random interleavings.
+    pub fn seed(mut self, seed: u64) -> Builder {
+        self.seed = seed;
+        self
+    }
+
+    /// Configures whether every possible interleaving should be enumerated
+    /// instead of picking interleavings at random.
+    pub fn exhaustive(mut self, exhaustive: bool) -> Builder {
+        self.exhaustive = exhaustive;
+        self
+    }
+
+    /// Configures whether waits on condition variables may wake up without a
+    /// notification.
+    pub fn spurious_wakeups(mut self, spurious: bool) -> Builder {
+        self.spurious_wakeups = spurious;
+        self
+    }
+
+    /// Runs `f` repeatedly under a number of interleavings.
+    ///
+    /// # Panics
+    ///
+    /// Panics if any execution panics or deadlocks. The message contains the
+    /// configuration and the schedule which can be passed to `replay` to
+    /// reproduce the failure.
+    pub fn check(&self, f: fn()) {
+        let mut rng: XorShiftRng = SeedableRng::from_seed(seed(self.seed));
+        let mut replay = Vec::new();
+        let mut complete = !self.exhaustive;
+        for i in range(0, self.iterations) {
+            let outcome = if self.exhaustive {
+                execute(f, replay.clone(), None, self)
+            } else {
+                let seed = [rng.next_u32() | 1, rng.next_u32(),
+                            rng.next_u32(), rng.next_u32()];
+                execute(f, Vec::new(), Some(SeedableRng::from_seed(seed)),
+                        self)
+            };
+            match outcome.failure {
+                Some(msg) => {
+                    panic!("model execution {} failed: {}\n\
+                            replay with schedule: {}\n\
+                            and configuration: {}",
+                           i, msg, outcome.schedule, self)
+                }
+                None => {}
+            }
+            if self.exhaustive {
+                match next_schedule(&outcome) {
+                    Some(s) => replay = s,
+                    None => { complete = true; break }
+                }
+            }
+        }
+        if !complete {
+            let _ = writeln!(&mut stdio::stderr(),
+                             "warning: model checking stopped after {} \
+                              executions without exploring every \
+                              interleaving",
+                             self.iterations);
+        }
+    }
+
+    /// Runs `f` once, following the schedule specified.
+    ///
+    /// The schedule is one that was reported by a previous failure of `check`
+    /// on a `Builder` with the same configuration, as choices such as
+    /// spurious wakeups are only offered when enabled. This function will
+    /// panic with the same failure if it occurs again.
+    pub fn replay(&self, schedule: &[uint], f: fn()) {
+        let outcome = execute(f, schedule.to_vec(), None, self);
+        match outcome.failure {
+            Some(msg) => {
+                panic!("model execution failed during replay: {}", msg)
+            }
+            None => {}
+        }
+    }
+}
+
+fn seed(seed: u64) -> [u32, ..4] {
+    [seed as u32 | 1, (seed >> 32) as u32, 0x193a6754, 0xa8a7d469]
+}
+
+// Finds the next schedule to explore in a depth-first enumeration of all
+// choices, or `None` if all of them have been explored.
+fn next_schedule(outcome: &Outcome) -> Option<Vec<uint>> {
+    let mut i = outcome.schedule.len();
+    while i > 0 {
+        i -= 1;
+        if outcome.schedule[i] + 1 < outcome.branches[i] {
+            let mut next = outcome.schedule.slice_to(i).to_vec();
+            next.push(outcome.schedule[i] + 1);
+            return Some(next)
+        }
+    }
+    None
+}
+
+fn execute(f: fn(), replay: Vec<uint>, rng: Option<XorShiftRng>,
+           builder: &Builder) -> Outcome {
+    let (done_tx, done_rx) = channel();
+    let shared = Arc::new(StdMutex::new(Execution {
+        threads: Vec::new(),
+        live: 0,
+        schedule: Vec::new(),
+        branches: Vec::new(),
+        replay: replay,
+        rng: rng,
+        spurious_wakeups: builder.spurious_wakeups,
+        failure: None,
+        aborting: false,
+        done: done_tx,
+    }));
+    launch(shared.clone(), proc() f());
+    {
+        let mut ex = shared.lock();
+        ex.wake(0);
+    }
+    done_rx.recv();
+
+    let mut ex = shared.lock();
+    Outcome {
+        schedule: ex.schedule.clone(),
+        branches: ex.branches.clone(),
+        failure: ex.failure.take(),
+    }
+}
+
+fn launch(shared: Arc<StdMutex<Execution>>, f: proc(): Send) -> uint {
+    let (tx, rx) = channel();
+    let id = {
+        let mut ex = shared.lock();
+        ex.threads.push(Thread {
+            blocked_on: None,
+            timed: false,
+            timed_out: false,
+            finished: false,
+            wake: tx,
+        });
+        ex.live += 1;
+        ex.threads.len() - 1
+    };
+    let shared2 = shared.clone();
+    task::spawn(proc() {
+        let result = task::try(proc() {
+            CURRENT.with(|c| {
+                *c.borrow_mut() = Some(Context {
+                    shared: shared2,
+                    id: id,
+                    wake: rx,
+                });
+            });
+            wait_turn();
+            f()
+        });
+        finish(&shared, id, result);
+    });
+    id
+}
+
+fn finish(shared: &Arc<StdMutex<Execution>>, id: uint,
+          result: Result<(), Box<Any + Send>>) {
+    let mut ex = shared.lock();
+    ex.threads[id].finished = true;
+    match result {
+        Ok(()) => {}
+        Err(e) => {
+            let msg = format!("thread {} panicked: {}", id, message(&e));
+            ex.fail(msg);
+        }
+    }
+    ex.live -= 1;
+    if ex.live == 0 {
+        let _ = ex.done.send_opt(());
+    } else if !ex.aborting {
+        ex.unblock(join_key(id), true);
+        ex.switch();
+    }
+}
+
+fn message(e: &Box<Any + Send>) -> String {
+    match e.downcast_ref::<&'static str>() {
+        Some(s) => return s.to_string(),
+        None => {}
+    }
+    match e.downcast_ref::<String>() {
+        Some(s) => s.clone(),
+        None => "Box<Any>".to_string(),
+    }
+}
+
+fn join_key(id: uint) -> uint { uint::MAX - id }
+
+fn context() -> Option<(Arc<StdMutex<Execution>>, uint)> {
+    CURRENT.with(|c| {
+        c.borrow().as_ref().map(|c| (c.shared.clone(), c.id))
+    })
+}
+
+// Blocks the current thread until the scheduler has picked it to run.
+fn wait_turn() {
+    let shared = CURRENT.with(|c| {
+        let c = c.borrow();
+        let c = c.as_ref().unwrap();
+        c.wake.recv();
+        c.shared.clone()
+    });
+    if shared.lock().aborting && !task::failing() {
+        panic!("model execution aborted");
+    }
+}
+
+/// Gives the scheduler a chance to switch to another thread.
+///
+/// This is called at every visible operation of a simulated primitive.
+pub fn yield_point() {
+    let (shared, _) = match context() {
+        Some(c) => c,
+        None => return,
+    };
+    {
+        let mut ex = shared.lock();
+        if ex.aborting { return }
+        ex.switch();
+    }
+    wait_turn();
+}
+
+// Blocks the current thread on the object identified by `key` until another
+// thread calls `unblock` with the same key. Returns whether the thread was
+// woken because its timed wait elapsed.
+fn block(key: uint, timed: bool) -> bool {
+    let (shared, me) = match context() {
+        Some(c) => c,
+        None => { task::deschedule(); return false }
+    };
+    {
+        let mut ex = shared.lock();
+        if ex.aborting {
+            drop(ex);
+            if !task::failing() { panic!("model execution aborted") }
+            task::deschedule();
+            return false
+        }
+        ex.threads[me].blocked_on = Some(key);
+        ex.threads[me].timed = timed;
+        ex.threads[me].timed_out = false;
+        ex.switch();
+    }
+    wait_turn();
+    let ex = shared.lock();
+    ex.threads[me].timed_out
+}
+
+// Wakes up threads blocked on `key`, either one of them or all of them.
+fn unblock(key: uint, all: bool) {
+    match context() {
+        Some((shared, _)) => shared.lock().unblock(key, all),
+        None => {}
+    }
+}
+
+// Returns whether the current execution allows spurious wakeups, or `None` if
+// the current thread isn't part of an execution.
+fn spurious_wakeups() -> Option<bool> {
+    context().map(|(shared, _)| shared.lock().spurious_wakeups)
+}
+
+// Picks between `n` alternatives at a choice point of the current execution.
+fn choose(n: uint) -> uint {
+    match context() {
+        Some((shared, _)) => shared.lock().choose(n),
+        None => 0,
+    }
+}
+
+impl Execution {
+    fn choose(&mut self, n: uint) -> uint {
+        if n <= 1 { return 0 }
+        let pos = self.schedule.len();
+        let c = if pos < self.replay.len() {
+            self.replay[pos] % n
+        } else {
+            match self.rng {
+                Some(ref mut rng) => rng.gen_range(0, n),
+                None => 0,
+            }
+        };
+        self.schedule.push(c);
+        self.branches.push(n);
+        c
+    }
+
+    fn wake(&mut self, id: uint) {
+        let _ = self.threads[id].wake.send_opt(());
+    }
+
+    // Picks the next thread to run and wakes it up.
+    fn switch(&mut self) {
+        let mut runnable: Vec<uint> = range(0, self.threads.len()).filter(|&i| {
+            !self.threads[i].finished && self.threads[i].blocked_on.is_none()
+        }).collect();
+        if runnable.len() == 0 {
+            // Nothing can make progress, so either let a timed wait expire or
+            // report a deadlock.
+            let timed: Vec<uint> = range(0, self.threads.len()).filter(|&i| {
+                self.threads[i].blocked_on.is_some() && self.threads[i].timed
+            }).collect();
+            if timed.len() == 0 {
+                self.fail("deadlock: all threads are blocked".to_string());
+                return
+            }
+            let i = timed[self.choose(timed.len())];
+            self.threads[i].blocked_on = None;
+            self.threads[i].timed_out = true;
+            runnable.push(i);
+        }
+        let next = runnable[self.choose(runnable.len())];
+        self.wake(next);
+    }
+
+    fn unblock(&mut self, key: uint, all: bool) {
+        if self.aborting { return }
+        let waiting: Vec<uint> = range(0, self.threads.len()).filter(|&i| {
+            self.threads[i].blocked_on == Some(key)
+        }).collect();
+        if waiting.len() == 0 { return }
+        if all {
+            for &i in waiting.iter() {
+                self.threads[i].blocked_on = None;
+            }
+        } else {
+            let i = waiting[self.choose(waiting.len())];
+            self.threads[i].blocked_on = None;
+        }
+    }
+
+    // Records a failure and lets all threads run freely so they can unwind.
+    fn fail(&mut self, msg: String) {
+        if self.failure.is_none() {
+            self.failure = Some(msg);
+        }
+        self.aborting = true;
+        for t in self.threads.iter() {
+            if !t.finished {
+                let _ = t.wake.send_opt(());
+            }
+        }
+    }
+}
+
+/// A simulated mutex.
+pub struct Mutex { locked: AtomicBool }
+
+/// Static initializer for simulated mutexes.
+pub const MUTEX_INIT: Mutex = Mutex { locked: atomic::INIT_ATOMIC_BOOL };
+
+impl Mutex {
+    #[allow(missing_docs)]
+    pub unsafe fn new() -> Mutex { MUTEX_INIT }
+    #[allow(missing_docs)]
+    pub unsafe fn lock(&self) {
+        yield_point();
+        while self.locked.swap(true, atomic::SeqCst) {
+            block(self.key(), false);
+        }
+    }
+    #[allow(missing_docs)]
+    pub unsafe fn try_lock(&self) -> bool {
+        yield_point();
+        !self.locked.swap(true, atomic::SeqCst)
+    }
+    #[allow(missing_docs)]
+    pub unsafe fn unlock(&self) {
+        self.release();
+        yield_point();
+    }
+    #[allow(missing_docs)]
+    pub unsafe fn destroy(&self) {}
+
+    fn release(&self) {
+        self.locked.store(false, atomic::SeqCst);
+        unblock(self.key(), true);
+    }
+
+    fn key(&self) -> uint { self as *const _ as uint }
+}
+
+/// A simulated condition variable.
+pub struct Condvar { seq: AtomicUint }
+
+/// Static initializer for simulated condition variables.
+pub const CONDVAR_INIT: Condvar = Condvar { seq: atomic::INIT_ATOMIC_UINT };
+
+impl Condvar {
+    #[allow(missing_docs)]
+    pub unsafe fn new() -> Condvar { CONDVAR_INIT }
+    #[allow(missing_docs)]
+    pub unsafe fn init(&mut self) {}
+    #[allow(missing_docs)]
+    pub unsafe fn notify_one(&self) {
+        yield_point();
+        self.seq.fetch_add(1, atomic::SeqCst);
+        unblock(self.key(), false);
+    }
+    #[allow(missing_docs)]
+    pub unsafe fn notify_all(&self) {
+        yield_point();
+        self.seq.fetch_add(1, atomic::SeqCst);
+        unblock(self.key(), true);
+    }
+    #[allow(missing_docs)]
+    pub unsafe fn wait(&self, mutex: &Mutex) {
+        let seq = self.seq.load(atomic::SeqCst);
+        mutex.release();
+        match spurious_wakeups() {
+            Some(spurious) => {
+                if !spurious || choose(2) == 0 {
+                    block(self.key(), false);
+                }
+            }
+            // Outside of an execution the condvar is simply spun on.
+            None => {
+                while self.seq.load(atomic::SeqCst) == seq {
+                    task::deschedule();
+                }
+            }
+        }
+        mutex.lock();
+    }
+    #[allow(missing_docs)]
+    pub unsafe fn wait_timeout(&self, mutex: &Mutex, dur: Duration) -> bool {
+        let seq = self.seq.load(atomic::SeqCst);
+        mutex.release();
+        let ret = match spurious_wakeups() {
+            // Either block until notified (or until the scheduler decides
+            // that the timeout elapsed), time out immediately, or wake up
+            // spuriously.
+            Some(spurious) => {
+                match choose(if spurious {3} else {2}) {
+                    0 => !block(self.key(), true),
+                    1 => false,
+                    _ => true,
+                }
+            }
+            None => {
+                let deadline = time::monotonic() + dur;
+                let mut notified = false;
+                while !notified && time::monotonic() < deadline {
+                    task::deschedule();
+                    notified = self.seq.load(atomic::SeqCst) != seq;
+                }
+                notified
+            }
+        };
+        mutex.lock();
+        ret
+    }
+    #[allow(missing_docs)]
+    pub unsafe fn wait_deadline(&self, mutex: &Mutex,
+                                deadline: Duration) -> bool {
+        let dur = deadline - time::monotonic();
+        self.wait_timeout(mutex, cmp::max(dur, Duration::zero()))
+    }
+    #[allow(missing_docs)]
+    pub unsafe fn destroy(&self) {}
+
+    fn key(&self) -> uint { self as *const _ as uint }
+}
+
+/// A simulated reader-writer lock.
+pub struct RWLock { state: AtomicUint }
+
+/// Static initializer for simulated rwlocks.
+pub const RWLOCK_INIT: RWLock = RWLock { state: atomic::INIT_ATOMIC_UINT };
+
+// Value of `RWLock::state` while write-locked, otherwise it's the number of
+// readers.
+const WRITER: uint = uint::MAX;
+
+impl RWLock {
+    #[allow(missing_docs)]
+    pub unsafe fn new() -> RWLock { RWLOCK_INIT }
+    #[allow(missing_docs)]
+    pub unsafe fn read(&self) {
+        yield_point();
+        while !self.try_read_inner() {
+            block(self.key(), false);
+        }
+    }
+    #[allow(missing_docs)]
+    pub unsafe fn try_read(&self) -> bool {
+        yield_point();
+        self.try_read_inner()
+    }
+    #[allow(missing_docs)]
+    pub unsafe fn read_timeout(&self, dur: Duration) -> bool {
+        yield_point();
+        self.acquire_timeout(dur, || self.try_read_inner())
+    }
+    #[allow(missing_docs)]
+    pub unsafe fn write(&self) {
+        yield_point();
+        while self.state.compare_and_swap(0, WRITER, atomic::SeqCst) != 0 {
+            block(self.key(), false);
+        }
+    }
+    #[allow(missing_docs)]
+    pub unsafe fn try_write(&self) -> bool {
+        yield_point();
+        self.state.compare_and_swap(0, WRITER, atomic::SeqCst) == 0
+    }
+    #[allow(missing_docs)]
+    pub unsafe fn write_timeout(&self, dur: Duration) -> bool {
+        yield_point();
+        self.acquire_timeout(dur, || {
+            self.state.compare_and_swap(0, WRITER, atomic::SeqCst) == 0
+        })
+    }
+    #[allow(missing_docs)]
+    pub unsafe fn read_unlock(&self) {
+        if self.state.fetch_sub(1, atomic::SeqCst) == 1 {
+            unblock(self.key(), true);
+        }
+        yield_point();
+    }
+    #[allow(missing_docs)]
+    pub unsafe fn write_unlock(&self) {
+        self.state.store(0, atomic::SeqCst);
+        unblock(self.key(), true);
+        yield_point();
+    }
+    #[allow(missing_docs)]
+    pub unsafe fn destroy(&self) {}
+
+    // Within an execution the scheduler decides when a timed acquisition
+    // gives up, otherwise the real time is consulted.
+    unsafe fn acquire_timeout(&self, dur: Duration, f: || -> bool) -> bool {
+        if context().is_some() {
+            while !f() {
+                if block(self.key(), true) { return false }
+            }
+            return true
+        }
+        let deadline = time::monotonic() + dur;
+        loop {
+            if f() { return true }
+            if time::monotonic() >= deadline { return false }
+            task::deschedule();
+        }
+    }
+
+    fn try_read_inner(&self) -> bool {
+        loop {
+            let cur = self.state.load(atomic::SeqCst);
+            if cur == WRITER { return false }
+            if self.state.compare_and_swap(cur, cur + 1,
+                                           atomic::SeqCst) == cur {
+                return true
+            }
+        }
+    }
+
+    fn key(&self) -> uint { self as *const _ as uint }
+}
+
+/// Simulated atomics, each operation of which is a scheduling point.
+pub mod atomic {
+    use std::sync::atomic as std_atomic;
+    use super::yield_point;
+
+    pub use std::sync::atomic::{Ordering, Relaxed, Release, Acquire, AcqRel};
+    pub use std::sync::atomic::{SeqCst, fence};
+
+    /// A simulated signed atomic integer.
+    pub struct AtomicInt { inner: std_atomic::AtomicInt }
+
+    /// Static initializer for simulated `AtomicInt`s.
+    pub const INIT_ATOMIC_INT: AtomicInt = AtomicInt {
+        inner: std_atomic::INIT_ATOMIC_INT,
+    };
+
+    #[allow(missing_docs)]
+    impl AtomicInt {
+        pub fn new(v: int) -> AtomicInt {
+            AtomicInt { inner: std_atomic::AtomicInt::new(v) }
+        }
+        pub fn load(&self, order: Ordering) -> int {
+            yield_point();
+            self.inner.load(order)
+        }
+        pub fn store(&self, val: int, order: Ordering) {
+            yield_point();
+            self.inner.store(val, order)
+        }
+        pub fn swap(&self, val: int, order: Ordering) -> int {
+            yield_point();
+            self.inner.swap(val, order)
+        }
+        pub fn compare_and_swap(&self, old: int, new: int,
+                                order: Ordering) -> int {
+            yield_point();
+            self.inner.compare_and_swap(old, new, order)
+        }
+        pub fn fetch_add(&self, val: int, order: Ordering) -> int {
+            yield_point();
+            self.inner.fetch_add(val, order)
+        }
+        pub fn fetch_sub(&self, val: int, order: Ordering) -> int {
+            yield_point();
+            self.inner.fetch_sub(val, order)
+        }
+    }
+
+    /// A simulated unsigned atomic integer.
+    pub struct AtomicUint { inner: std_atomic::AtomicUint }
+
+    /// Static initializer for simulated `AtomicUint`s.
+    pub const INIT_ATOMIC_UINT: AtomicUint = AtomicUint {
+        inner: std_atomic::INIT_ATOMIC_UINT,
+    };
+
+    #[allow(missing_docs)]
+    impl AtomicUint {
+        pub fn new(v: uint) -> AtomicUint {
+            AtomicUint { inner: std_atomic::AtomicUint::new(v) }
+        }
+        pub fn load(&self, order: Ordering) -> uint {
+            yield_point();
+            self.inner.load(order)
+        }
+        pub fn store(&self, val: uint, order: Ordering) {
+            yield_point();
+            self.inner.store(val, order)
+        }
+        pub fn swap(&self, val: uint, order: Ordering) -> uint {
+            yield_point();
+            self.inner.swap(val, order)
+        }
+        pub fn compare_and_swap(&self, old: uint, new: uint,
+                                order: Ordering) -> uint {
+            yield_point();
+            self.inner.compare_and_swap(old, new, order)
+        }
+        pub fn fetch_add(&self, val: uint, order: Ordering) -> uint {
+            yield_point();
+            self.inner.fetch_add(val, order)
+        }
+        pub fn fetch_sub(&self, val: uint, order: Ordering) -> uint {
+            yield_point();
+            self.inner.fetch_sub(val, order)
+        }
+    }
+
+    /// A simulated atomic raw pointer.
+    pub struct AtomicPtr<T> { inner: std_atomic::AtomicPtr<T> }
+
+    #[allow(missing_docs)]
+    impl<T> AtomicPtr<T> {
+        pub fn new(p: *mut T) -> AtomicPtr<T> {
+            AtomicPtr { inner: std_atomic::AtomicPtr::new(p) }
+        }
+        pub fn load(&self, order: Ordering) -> *mut T {
+            yield_point();
+            self.inner.load(order)
+        }
+        pub fn store(&self, ptr: *mut T, order: Ordering) {
+            yield_point();
+            self.inner.store(ptr, order)
+        }
+        pub fn swap(&self, ptr: *mut T, order: Ordering) -> *mut T {
+            yield_point();
+            self.inner.swap(ptr, order)
+        }
+        pub fn compare_and_swap(&self, old: *mut T, new: *mut T,
+                                order: Ordering) -> *mut T {
+            yield_point();
+            self.inner.compare_and_swap(old, new, order)
+        }
+    }
+
+    /// A simulated atomic boolean.
+    pub struct AtomicBool { inner: std_atomic::AtomicBool }
+
+    /// Static initializer for simulated `AtomicBool`s.
+    pub const INIT_ATOMIC_BOOL: AtomicBool = AtomicBool {
+        inner: std_atomic::INIT_ATOMIC_BOOL,
+    };
+
+    #[allow(missing_docs)]
+    impl AtomicBool {
+        pub fn new(v: bool) -> AtomicBool {
+            AtomicBool { inner: std_atomic::AtomicBool::new(v) }
+        }
+        pub fn load(&self, order: Ordering) -> bool {
+            yield_point();
+            self.inner.load(order)
+        }
+        pub fn store(&self, val: bool, order: Ordering) {
+            yield_point();
+            self.inner.store(val, order)
+        }
+        pub fn swap(&self, val: bool, order: Ordering) -> bool {
+            yield_point();
+            self.inner.swap(val, order)
+        }
+        pub fn compare_and_swap(&self, old: bool, new: bool,
+                                order: Ordering) -> bool {
+            yield_point();
+            self.inner.compare_and_swap(old, new, order)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use {Mutex, Condvar};
+    use super::{spawn, Builder};
+    use super::atomic::{AtomicInt, SeqCst};
+
+    #[test]
+    fn mutex_counter() {
+        fn body() {
+            let m = Arc::new(Mutex::new(0u));
+            let m2 = m.clone();
+            let t = spawn(proc() { *m2.lock() += 1; });
+            *m.lock() += 1;
+            t.join();
+            assert_eq!(*m.lock(), 2);
+        }
+        Builder::new().exhaustive(true).check(body);
+    }
+
+    #[test]
+    #[should_fail]
+    fn lost_update() {
+        fn body() {
+            let a = Arc::new(AtomicInt::new(0));
+            let a2 = a.clone();
+            let t = spawn(proc() {
+                let v = a2.load(SeqCst);
+                a2.store(v + 1, SeqCst);
+            });
+            let v = a.load(SeqCst);
+            a.store(v + 1, SeqCst);
+            t.join();
+            assert_eq!(a.load(SeqCst), 2);
+        }
+        Builder::new().exhaustive(true).check(body);
+    }
+
+    #[test]
+    #[should_fail]
+    fn deadlock() {
+        fn body() {
+            let a = Arc::new((Mutex::new(()), Mutex::new(())));
+            let a2 = a.clone();
+            let t = spawn(proc() {
+                let &(ref m1, ref m2) = &*a2;
+                let _g2 = m2.lock();
+                let _g1 = m1.lock();
+            });
+            {
+                let &(ref m1, ref m2) = &*a;
+                let _g1 = m1.lock();
+                let _g2 = m2.lock();
+            }
+            t.join();
+        }
+        Builder::new().exhaustive(true).check(body);
+    }
+
+    #[test]
+    #[should_fail]
+    fn missing_predicate_loop() {
+        fn body() {
+            let pair = Arc::new((Mutex::new(false), Condvar::new()));
+            let pair2 = pair.clone();
+            let t = spawn(proc() {
+                let &(ref lock, ref cvar) = &*pair2;
+                *lock.lock() = true;
+                cvar.notify_one();
+            });
+            let &(ref lock, ref cvar) = &*pair;
+            let started = lock.lock();
+            if !*started {
+                cvar.wait(&started);
+            }
+            assert!(*started);
+            drop(started);
+            t.join();
+        }
+        Builder::new().exhaustive(true).check(body);
+    }
+
+    #[test]
+    fn condvar_handshake() {
+        fn body() {
+            let pair = Arc::new((Mutex::new(false), Condvar::new()));
+            let pair2 = pair.clone();
+            let t = spawn(proc() {
+                let &(ref lock, ref cvar) = &*pair2;
+                *lock.lock() = true;
+                cvar.notify_one();
+            });
+            let &(ref lock, ref cvar) = &*pair;
+            let started = lock.lock();
+            while !*started {
+                cvar.wait(&started);
+            }
+            drop(started);
+            t.join();
+        }
+        Builder::new().exhaustive(true).check(body);
+    }
+
+    #[test]
+    fn replay_with_config() {
+        fn body() {
+            let pair = Arc::new((Mutex::new(false), Condvar::new()));
+            let pair2 = pair.clone();
+            let t = spawn(proc() {
+                let &(ref lock, ref cvar) = &*pair2;
+                *lock.lock() = true;
+                cvar.notify_one();
+            });
+            let &(ref lock, ref cvar) = &*pair;
+            let started = lock.lock();
+            if !*started {
+                cvar.wait(&started);
+            }
+            assert!(*started);
+            drop(started);
+            t.join();
+        }
+        // without spurious wakeups this body is correct under every schedule
+        let b = Builder::new().spurious_wakeups(false);
+        b.replay(&[1, 1, 0, 1, 0, 1], body);
+        b.clone().exhaustive(true).check(body);
+    }
+}
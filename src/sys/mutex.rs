@@ -48,7 +48,7 @@ impl Mutex {
     pub unsafe fn destroy(&self) { self.0.destroy() }
 }
 
-#[cfg(unix)]
+#[cfg(all(unix, not(feature = "model")))]
 mod imp {
     use std::cell::UnsafeCell;
     use sys::ffi;
@@ -93,7 +93,15 @@ mod imp {
     }
 }
 
-#[cfg(windows)]
+#[cfg(feature = "model")]
+mod imp {
+    pub use sys::model::{Mutex, MUTEX_INIT};
+
+    #[inline]
+    pub unsafe fn raw(m: &super::Mutex) -> &Mutex { &m.0 }
+}
+
+#[cfg(all(windows, not(feature = "model")))]
 mod imp {
     use std::sync::atomic;
     use alloc::{mod, heap};
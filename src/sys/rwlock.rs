@@ -1,3 +1,8 @@
+use std::time::Duration;
+
+#[cfg(not(feature = "model"))]
+use sys::time;
+
 /// An OS-based reader-writer lock.
 ///
 /// This structure is entirely unsafe and serves as the lowest layer of a
@@ -20,7 +25,7 @@ impl RWLock {
     /// thread to do so.
     ///
     /// Behavior is undefined if the rwlock has been moved between this and any
-    /// previous methodo call.
+    /// previous method call.
     #[inline]
     pub unsafe fn read(&self) { self.0.read() }
 
@@ -30,15 +35,33 @@ impl RWLock {
     /// This function does not block the current thread.
     ///
     /// Behavior is undefined if the rwlock has been moved between this and any
-    /// previous methodo call.
+    /// previous method call.
     #[inline]
     pub unsafe fn try_read(&self) -> bool { self.0.try_read() }
 
+    /// Acquire shared access to the underlying lock, blocking the current
+    /// thread for at most `dur`.
+    ///
+    /// Returns whether shared access was acquired. The duration is measured
+    /// with the monotonic clock, and may be rounded up to the granularity of
+    /// the platform's timers.
+    ///
+    /// This blocks on the system rwlock on Linux if the C library provides
+    /// `pthread_rwlock_clockrdlock`. Elsewhere the lock is polled for, which
+    /// may add up to a millisecond of latency to the acquisition.
+    ///
+    /// Behavior is undefined if the rwlock has been moved between this and any
+    /// previous method call.
+    #[inline]
+    pub unsafe fn read_timeout(&self, dur: Duration) -> bool {
+        self.0.read_timeout(dur)
+    }
+
     /// Acquire write access to the underlying lock, blocking the current thread
     /// to do so.
     ///
     /// Behavior is undefined if the rwlock has been moved between this and any
-    /// previous methodo call.
+    /// previous method call.
     #[inline]
     pub unsafe fn write(&self) { self.0.write() }
 
@@ -48,10 +71,22 @@ impl RWLock {
     /// This function does not block the current thread.
     ///
     /// Behavior is undefined if the rwlock has been moved between this and any
-    /// previous methodo call.
+    /// previous method call.
     #[inline]
     pub unsafe fn try_write(&self) -> bool { self.0.try_write() }
 
+    /// Acquire write access to the underlying lock, blocking the current thread
+    /// for at most `dur`.
+    ///
+    /// Returns whether write access was acquired. See `read_timeout`.
+    ///
+    /// Behavior is undefined if the rwlock has been moved between this and any
+    /// previous method call.
+    #[inline]
+    pub unsafe fn write_timeout(&self, dur: Duration) -> bool {
+        self.0.write_timeout(dur)
+    }
+
     /// Unlock previously acquired shared access to this lock.
     ///
     /// Behavior is undefined if the current thread does not have shared access.
@@ -73,10 +108,23 @@ impl RWLock {
     pub unsafe fn destroy(&self) { self.0.destroy() }
 }
 
-#[cfg(unix)]
+// Platforms without timed acquisition of rwlocks on the monotonic clock
+// instead repeatedly try to acquire them until the timeout has elapsed.
+#[cfg(not(feature = "model"))]
+unsafe fn poll(dur: Duration, f: || -> bool) -> bool {
+    let deadline = time::monotonic() + dur;
+    time::poll(f, || time::monotonic() >= deadline)
+}
+
+#[cfg(all(unix, not(feature = "model")))]
 mod imp {
     use std::cell::UnsafeCell;
+    use std::time::Duration;
+    #[cfg(target_os = "linux")] use std::mem;
+    #[cfg(target_os = "linux")] use libc;
+
     use sys::ffi;
+    #[cfg(target_os = "linux")] use sys::time;
 
     pub struct RWLock { inner: UnsafeCell<ffi::pthread_rwlock_t> }
 
@@ -100,6 +148,17 @@ mod imp {
         pub unsafe fn try_read(&self) -> bool {
             ffi::pthread_rwlock_tryrdlock(self.inner.get()) == 0
         }
+        #[cfg(target_os = "linux")]
+        pub unsafe fn read_timeout(&self, dur: Duration) -> bool {
+            match clocklock(ffi::pthread_rwlock_clockrdlock, self, dur) {
+                Some(acquired) => acquired,
+                None => super::poll(dur, || self.try_read()),
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        pub unsafe fn read_timeout(&self, dur: Duration) -> bool {
+            super::poll(dur, || self.try_read())
+        }
         #[inline]
         pub unsafe fn write(&self) {
             let r = ffi::pthread_rwlock_wrlock(self.inner.get());
@@ -109,6 +168,17 @@ mod imp {
         pub unsafe fn try_write(&self) -> bool {
             ffi::pthread_rwlock_trywrlock(self.inner.get()) == 0
         }
+        #[cfg(target_os = "linux")]
+        pub unsafe fn write_timeout(&self, dur: Duration) -> bool {
+            match clocklock(ffi::pthread_rwlock_clockwrlock, self, dur) {
+                Some(acquired) => acquired,
+                None => super::poll(dur, || self.try_write()),
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        pub unsafe fn write_timeout(&self, dur: Duration) -> bool {
+            super::poll(dur, || self.try_write())
+        }
         #[inline]
         pub unsafe fn read_unlock(&self) {
             let r = ffi::pthread_rwlock_unlock(self.inner.get());
@@ -122,11 +192,37 @@ mod imp {
             debug_assert_eq!(r, 0);
         }
     }
+
+    // Acquires `lock` with one of the weakly linked `pthread_rwlock_clock*`
+    // functions, `f`, with a deadline on the monotonic clock. Returns `None`
+    // if the C library doesn't provide the function.
+    #[cfg(target_os = "linux")]
+    unsafe fn clocklock(f: *const libc::c_void, lock: &RWLock, dur: Duration)
+                        -> Option<bool> {
+        if f.is_null() { return None }
+        assert!(dur >= Duration::nanoseconds(0));
+        let f: ffi::pthread_rwlock_clocklock = mem::transmute(f);
+        let zero = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+        let deadline = time::timespec_add(zero, time::monotonic() + dur);
+        let r = f(lock.inner.get(), ffi::CLOCK_MONOTONIC, &deadline);
+        if r != 0 {
+            debug_assert_eq!(r as int, libc::ETIMEDOUT as int);
+            Some(false)
+        } else {
+            Some(true)
+        }
+    }
+}
+
+#[cfg(feature = "model")]
+mod imp {
+    pub use sys::model::{RWLock, RWLOCK_INIT};
 }
 
-#[cfg(windows)]
+#[cfg(all(windows, not(feature = "model")))]
 mod imp {
     use std::cell::UnsafeCell;
+    use std::time::Duration;
 
     use sys::ffi;
 
@@ -148,6 +244,10 @@ mod imp {
         pub unsafe fn try_read(&self) -> bool {
             ffi::TryAcquireSRWLockShared(self.inner.get()) != 0
         }
+        pub unsafe fn read_timeout(&self, dur: Duration) -> bool {
+            // SRW locks have no timed acquisition
+            super::poll(dur, || self.try_read())
+        }
         #[inline]
         pub unsafe fn write(&self) {
             ffi::AcquireSRWLockExclusive(self.inner.get())
@@ -156,6 +256,9 @@ mod imp {
         pub unsafe fn try_write(&self) -> bool {
             ffi::TryAcquireSRWLockExclusive(self.inner.get()) != 0
         }
+        pub unsafe fn write_timeout(&self, dur: Duration) -> bool {
+            super::poll(dur, || self.try_write())
+        }
         #[inline]
         pub unsafe fn read_unlock(&self) {
             ffi::ReleaseSRWLockShared(self.inner.get())
@@ -0,0 +1,136 @@
+//! Access to the system clock
+
+use std::io::timer;
+#[cfg(unix)] use std::num::Int;
+use std::time::Duration;
+#[cfg(unix)] use libc;
+
+// How often `poll` retries its operation.
+const POLL_MS: i64 = 1;
+
+/// Returns the current wall-clock time of the system, represented as the
+/// duration since the Unix epoch.
+///
+/// This clock is not monotonic and may jump forwards or backwards if the
+/// system time is changed.
+pub unsafe fn realtime() -> Duration { imp::realtime() }
+
+/// Returns the current time of the system's monotonic clock, represented as the
+/// duration since an unspecified point in the past.
+///
+/// Unlike `realtime`, this clock is unaffected by changes to the system time,
+/// making it suitable for measuring timeouts.
+pub unsafe fn monotonic() -> Duration { imp::monotonic() }
+
+/// Repeatedly calls `f`, sleeping briefly between attempts, until it returns
+/// `true` or `expired` does, for timed operations which the system can't block
+/// on directly.
+///
+/// Returns whether `f` succeeded. `f` is always called at least once.
+#[doc(hidden)]
+pub fn poll(f: || -> bool, expired: || -> bool) -> bool {
+    loop {
+        if f() { return true }
+        if expired() { return false }
+        timer::sleep(Duration::milliseconds(POLL_MS));
+    }
+}
+
+/// Adds `dur` to `ts`, saturating at the largest representable time instead of
+/// overflowing.
+#[cfg(unix)]
+#[doc(hidden)]
+pub fn timespec_add(ts: libc::timespec, dur: Duration) -> libc::timespec {
+    let max: libc::time_t = Int::max_value();
+    let secs = dur.num_seconds();
+    let nsec = (dur - Duration::seconds(secs)).num_nanoseconds().unwrap()
+               as libc::c_long + ts.tv_nsec;
+    let secs = if secs > max as i64 {max} else {secs as libc::time_t};
+    let extra = (nsec / 1000000000) as libc::time_t;
+    match ts.tv_sec.checked_add(secs).and_then(|s| s.checked_add(extra)) {
+        Some(s) => libc::timespec { tv_sec: s, tv_nsec: nsec % 1000000000 },
+        None => libc::timespec { tv_sec: max, tv_nsec: 999999999 },
+    }
+}
+
+#[cfg(unix)]
+mod imp {
+    use std::time::Duration;
+    use libc;
+
+    use sys::ffi;
+
+    pub use self::os::monotonic;
+
+    pub unsafe fn realtime() -> Duration {
+        let mut tv = libc::timeval { tv_sec: 0, tv_usec: 0 };
+        let r = ffi::gettimeofday(&mut tv, 0 as *mut _);
+        debug_assert_eq!(r, 0);
+        Duration::seconds(tv.tv_sec as i64) +
+            Duration::microseconds(tv.tv_usec as i64)
+    }
+
+    #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+    mod os {
+        use std::time::Duration;
+        use libc;
+
+        use sys::ffi;
+
+        pub unsafe fn monotonic() -> Duration {
+            let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+            let r = ffi::clock_gettime(ffi::CLOCK_MONOTONIC, &mut ts);
+            debug_assert_eq!(r, 0);
+            Duration::seconds(ts.tv_sec as i64) +
+                Duration::nanoseconds(ts.tv_nsec as i64)
+        }
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    mod os {
+        use std::time::Duration;
+
+        use sys::ffi;
+
+        pub unsafe fn monotonic() -> Duration {
+            let mut info = ffi::mach_timebase_info { numer: 0, denom: 0 };
+            let r = ffi::mach_timebase_info(&mut info);
+            debug_assert_eq!(r, 0);
+            // Split the conversion to avoid overflowing the intermediate
+            // product for large tick counts.
+            let ticks = ffi::mach_absolute_time();
+            let (numer, denom) = (info.numer as u64, info.denom as u64);
+            let ns = ticks / denom * numer + ticks % denom * numer / denom;
+            Duration::nanoseconds(ns as i64)
+        }
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    use std::time::Duration;
+
+    use sys::ffi;
+
+    // Number of 100ns intervals between 1601-01-01 and 1970-01-01
+    const EPOCH_DIFFERENCE: i64 = 116444736000000000;
+
+    pub unsafe fn realtime() -> Duration {
+        let mut ft = ffi::FILETIME { dwLowDateTime: 0, dwHighDateTime: 0 };
+        ffi::GetSystemTimeAsFileTime(&mut ft);
+        let ticks = (ft.dwLowDateTime as i64) |
+                    ((ft.dwHighDateTime as i64) << 32);
+        Duration::microseconds((ticks - EPOCH_DIFFERENCE) / 10)
+    }
+
+    pub unsafe fn monotonic() -> Duration {
+        let mut frequency = 0;
+        let mut ticks = 0;
+        let r = ffi::QueryPerformanceFrequency(&mut frequency);
+        debug_assert!(r != 0);
+        let r = ffi::QueryPerformanceCounter(&mut ticks);
+        debug_assert!(r != 0);
+        Duration::seconds(ticks / frequency) +
+            Duration::nanoseconds(ticks % frequency * 1000000000 / frequency)
+    }
+}
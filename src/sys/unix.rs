@@ -3,9 +3,11 @@ use libc;
 pub use self::os::{PTHREAD_MUTEX_INITIALIZER, pthread_mutex_t};
 pub use self::os::{PTHREAD_COND_INITIALIZER, pthread_cond_t};
 pub use self::os::{PTHREAD_RWLOCK_INITIALIZER, pthread_rwlock_t};
+#[cfg(not(any(target_os = "macos", target_os = "ios")))]
+pub use self::os::{pthread_condattr_t, CLOCK_MONOTONIC};
 
 pub type pthread_mutexattr_t = libc::c_void;
-pub type pthread_condattr_t = libc::c_void;
+pub type clockid_t = libc::c_int;
 
 extern {
     // mutexes
@@ -35,6 +37,56 @@ extern {
     pub fn pthread_rwlock_unlock(lock: *mut pthread_rwlock_t) -> libc::c_int;
 }
 
+#[cfg(target_os = "linux")]
+extern {
+    pub fn sched_getcpu() -> libc::c_int;
+}
+
+// The timed rwlock functions taking a clock were only added in glibc 2.30, so
+// they are linked weakly and are null if the C library doesn't provide them.
+#[cfg(target_os = "linux")]
+extern {
+    #[linkage = "extern_weak"]
+    pub static pthread_rwlock_clockrdlock: *const libc::c_void;
+    #[linkage = "extern_weak"]
+    pub static pthread_rwlock_clockwrlock: *const libc::c_void;
+}
+
+#[cfg(target_os = "linux")]
+pub type pthread_rwlock_clocklock = extern "C" fn(*mut pthread_rwlock_t,
+                                                  clockid_t,
+                                                  *const libc::timespec)
+                                                  -> libc::c_int;
+
+#[cfg(not(any(target_os = "macos", target_os = "ios")))]
+extern {
+    pub fn pthread_cond_init(cond: *mut pthread_cond_t,
+                             attr: *const pthread_condattr_t) -> libc::c_int;
+    pub fn pthread_condattr_init(attr: *mut pthread_condattr_t) -> libc::c_int;
+    pub fn pthread_condattr_setclock(attr: *mut pthread_condattr_t,
+                                     clock_id: clockid_t) -> libc::c_int;
+    pub fn pthread_condattr_destroy(attr: *mut pthread_condattr_t) -> libc::c_int;
+    pub fn clock_gettime(clk_id: clockid_t,
+                         tp: *mut libc::timespec) -> libc::c_int;
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+#[repr(C)]
+pub struct mach_timebase_info {
+    pub numer: u32,
+    pub denom: u32,
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+extern {
+    pub fn pthread_cond_timedwait_relative_np(cond: *mut pthread_cond_t,
+                                              lock: *mut pthread_mutex_t,
+                                              reltime: *const libc::timespec)
+                                              -> libc::c_int;
+    pub fn mach_absolute_time() -> u64;
+    pub fn mach_timebase_info(info: *mut mach_timebase_info) -> libc::c_int;
+}
+
 #[cfg(any(target_os = "freebsd", target_os = "dragonfly"))]
 mod os {
     use libc;
@@ -42,6 +94,9 @@ mod os {
     pub type pthread_mutex_t = *mut libc::c_void;
     pub type pthread_cond_t = *mut libc::c_void;
     pub type pthread_rwlock_t = *mut libc::c_void;
+    pub type pthread_condattr_t = *mut libc::c_void;
+
+    pub const CLOCK_MONOTONIC: super::clockid_t = 4;
 
     pub const PTHREAD_MUTEX_INITIALIZER: pthread_mutex_t = 0 as *mut _;
     pub const PTHREAD_COND_INITIALIZER: pthread_cond_t = 0 as *mut _;
@@ -148,6 +203,10 @@ mod os {
         __align: libc::c_longlong,
         size: [u8, ..__SIZEOF_PTHREAD_RWLOCK_T],
     }
+    #[repr(C)]
+    pub struct pthread_condattr_t { __align: libc::c_int }
+
+    pub const CLOCK_MONOTONIC: super::clockid_t = 1;
 
     pub const PTHREAD_MUTEX_INITIALIZER: pthread_mutex_t = pthread_mutex_t {
         __align: 0,
@@ -170,6 +229,9 @@ mod os {
     pub struct pthread_mutex_t { value: libc::c_int }
     #[repr(C)]
     pub struct pthread_cond_t { value: libc::c_int }
+    pub type pthread_condattr_t = libc::c_long;
+
+    pub const CLOCK_MONOTONIC: super::clockid_t = 1;
     #[repr(C)]
     pub struct pthread_rwlock_t {
         lock: pthread_mutex_t,
@@ -1,4 +1,4 @@
-use libc::{BOOL, DWORD, c_void, LPVOID};
+use libc::{BOOL, DWORD, c_void, LPVOID, LARGE_INTEGER};
 use libc::types::os::arch::extra::BOOLEAN;
 
 pub type LPCRITICAL_SECTION = *mut c_void;
@@ -14,6 +14,11 @@ pub const CRITICAL_SECTION_SIZE: uint = 40;
 pub struct CONDITION_VARIABLE { pub ptr: LPVOID }
 #[repr(C)]
 pub struct SRWLOCK { pub ptr: LPVOID }
+#[repr(C)]
+pub struct FILETIME {
+    pub dwLowDateTime: DWORD,
+    pub dwHighDateTime: DWORD,
+}
 
 pub const CONDITION_VARIABLE_INIT: CONDITION_VARIABLE = CONDITION_VARIABLE {
     ptr: 0 as *mut _,
@@ -44,4 +49,12 @@ extern "system" {
     pub fn ReleaseSRWLockShared(SRWLock: LPSRWLOCK);
     pub fn TryAcquireSRWLockExclusive(SRWLock: LPSRWLOCK) -> BOOLEAN;
     pub fn TryAcquireSRWLockShared(SRWLock: LPSRWLOCK) -> BOOLEAN;
+
+    // time
+    pub fn GetSystemTimeAsFileTime(lpSystemTimeAsFileTime: *mut FILETIME);
+    pub fn QueryPerformanceCounter(lpPerformanceCount: *mut LARGE_INTEGER) -> BOOL;
+    pub fn QueryPerformanceFrequency(lpFrequency: *mut LARGE_INTEGER) -> BOOL;
+
+    // processors
+    pub fn GetCurrentProcessorNumber() -> DWORD;
 }
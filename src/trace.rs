@@ -0,0 +1,212 @@
+//! Recording of synchronization activity
+//!
+//! When this crate is compiled with the `trace` feature, the safe primitives
+//! (`Mutex`, `RWLock`, `Condvar`, `Semaphore`, `Barrier`, and `Once`) record an
+//! event each time they are acquired, released, waited on, or notified. Events
+//! are timestamped, tagged with the id of the thread which generated them, and
+//! are buffered locally in each thread to keep the overhead of recording low.
+//!
+//! The recorded events can be written out with `write` in the [Chrome trace
+//! event format][format], which can then be loaded into `chrome://tracing` to
+//! view a timeline of how threads contended on each primitive.
+//!
+//! [format]: https://docs.google.com/document/d/1CvAClvFfyA5R-PhYUmn5OOQtYMH4h6I0nSsKchNAySU
+//!
+//! Each thread's buffer is moved into the global trace when it fills up, when
+//! the thread exits, or when `flush` is called from that thread. Events which
+//! are still buffered in other running threads will not appear in the output of
+//! `write`, and events generated by a thread after its buffer has been
+//! destroyed on exit are not recorded at all.
+//!
+//! Timestamps are taken from the monotonic clock, so they are only meaningful
+//! relative to one another.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use std::io::File;
+//! use sync::{trace, Mutex};
+//!
+//! let m = Mutex::new(1u);
+//! drop(m.lock());
+//!
+//! let mut f = File::create(&Path::new("sync.json"));
+//! trace::write(&mut f).unwrap();
+//! ```
+
+use std::cell::RefCell;
+use std::io::IoResult;
+use std::mem;
+use std::sync::atomic::{mod, AtomicUint};
+
+use sys;
+
+// Number of events a thread will buffer before moving them to the global trace
+const FLUSH_THRESHOLD: uint = 4096;
+
+struct Event {
+    name: &'static str,
+    phase: char,
+    ts: i64,
+    tid: uint,
+    id: uint,
+}
+
+struct Buffer {
+    tid: uint,
+    events: Vec<Event>,
+}
+
+// Note that the global trace is protected by a raw `sys` mutex, the safe
+// primitives would otherwise attempt to record their own usage.
+static LOCK: sys::Mutex = sys::MUTEX_INIT;
+static mut EVENTS: *mut Vec<Event> = 0 as *mut Vec<Event>;
+static NEXT_TID: AtomicUint = atomic::INIT_ATOMIC_UINT;
+
+thread_local!(static BUFFER: RefCell<Buffer> = RefCell::new(Buffer {
+    tid: NEXT_TID.fetch_add(1, atomic::SeqCst) + 1,
+    events: Vec::new(),
+}))
+
+/// Moves all events buffered by the calling thread into the global trace.
+pub fn flush() {
+    if BUFFER.destroyed() { return }
+    BUFFER.with(|buf| buf.borrow_mut().flush());
+}
+
+/// Writes all events recorded so far to `w` as a JSON trace in the Chrome
+/// trace event format.
+///
+/// The calling thread's buffer is flushed before writing, and all events
+/// written are removed from the global trace.
+pub fn write(w: &mut Writer) -> IoResult<()> {
+    flush();
+    let events = take();
+
+    try!(write!(w, "{{\"traceEvents\":["));
+    for (i, e) in events.iter().enumerate() {
+        if i > 0 { try!(write!(w, ",")); }
+        try!(write!(w, "\n{{\"name\":\"{}\",\"cat\":\"sync\",\"ph\":\"{}\",\
+                        \"ts\":{},\"pid\":1,\"tid\":{}",
+                    e.name, e.phase, e.ts, e.tid));
+        match e.phase {
+            'b' | 'e' => try!(write!(w, ",\"id\":\"{:#x}\"", e.id)),
+            'i' => try!(write!(w, ",\"s\":\"t\",\"args\":{{\"id\":\"{:#x}\"}}",
+                               e.id)),
+            _ => try!(write!(w, ",\"args\":{{\"id\":\"{:#x}\"}}", e.id)),
+        }
+        try!(write!(w, "}}"));
+    }
+    try!(write!(w, "\n]}}\n"));
+    Ok(())
+}
+
+/// Discards all events recorded so far, including those buffered by the
+/// calling thread.
+pub fn clear() {
+    flush();
+    drop(take());
+}
+
+/// Records that the current thread has started blocking on `obj`.
+#[doc(hidden)]
+#[inline]
+pub fn begin<T>(name: &'static str, obj: &T) { record(name, 'B', obj) }
+
+/// Records that the current thread has stopped blocking on `obj`.
+#[doc(hidden)]
+#[inline]
+pub fn end<T>(name: &'static str, obj: &T) { record(name, 'E', obj) }
+
+/// Records that `obj` has been acquired by the current thread.
+///
+/// Acquisitions and releases are recorded as asynchronous events because
+/// guards are not necessarily dropped in the order that they were acquired.
+#[doc(hidden)]
+#[inline]
+pub fn acquire<T>(name: &'static str, obj: &T) { record(name, 'b', obj) }
+
+/// Records that `obj` has been released by the current thread.
+#[doc(hidden)]
+#[inline]
+pub fn release<T>(name: &'static str, obj: &T) { record(name, 'e', obj) }
+
+/// Records a one-off event, such as a notification, on `obj`.
+#[doc(hidden)]
+#[inline]
+pub fn instant<T>(name: &'static str, obj: &T) { record(name, 'i', obj) }
+
+fn record<T>(name: &'static str, phase: char, obj: &T) {
+    // Primitives may still be used by other thread-local destructors after the
+    // buffer has been torn down, in which case their events are dropped.
+    if BUFFER.destroyed() { return }
+    let ts = unsafe { sys::time::monotonic().num_microseconds().unwrap_or(0) };
+    let id = obj as *const T as uint;
+    BUFFER.with(|buf| {
+        let mut buf = buf.borrow_mut();
+        let tid = buf.tid;
+        buf.events.push(Event {
+            name: name,
+            phase: phase,
+            ts: ts,
+            tid: tid,
+            id: id,
+        });
+        if buf.events.len() >= FLUSH_THRESHOLD {
+            buf.flush();
+        }
+    });
+}
+
+fn take() -> Vec<Event> {
+    unsafe {
+        LOCK.lock();
+        let events = if EVENTS.is_null() {
+            Vec::new()
+        } else {
+            mem::replace(&mut *EVENTS, Vec::new())
+        };
+        LOCK.unlock();
+        events
+    }
+}
+
+impl Buffer {
+    fn flush(&mut self) {
+        if self.events.len() == 0 { return }
+        let events = mem::replace(&mut self.events, Vec::new());
+        unsafe {
+            LOCK.lock();
+            if EVENTS.is_null() {
+                EVENTS = mem::transmute(box Vec::<Event>::new());
+            }
+            (*EVENTS).extend(events.into_iter());
+            LOCK.unlock();
+        }
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) { self.flush() }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::MemWriter;
+    use {Mutex, Condvar};
+
+    #[test]
+    fn smoke() {
+        let m = Mutex::new(());
+        let c = Condvar::new();
+        drop(m.lock());
+        c.notify_one();
+
+        let mut w = MemWriter::new();
+        super::write(&mut w).unwrap();
+        let s = String::from_utf8(w.unwrap()).unwrap();
+        assert!(s.as_slice().starts_with("{\"traceEvents\":["));
+        assert!(s.as_slice().contains("\"name\":\"Mutex\""));
+        assert!(s.as_slice().contains("\"name\":\"Condvar::notify_one\""));
+    }
+}
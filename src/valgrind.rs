@@ -0,0 +1,83 @@
+//! Annotations for Valgrind's Helgrind and DRD tools
+//!
+//! Helgrind and DRD understand the pthread primitives used by this crate, but
+//! they have no knowledge of the synchronization performed by the primitives
+//! built on top of them (such as the atomic fast path of `Once`) and report
+//! false races as a result. When the `valgrind` feature is enabled, these
+//! primitives issue happens-before/happens-after client requests to describe
+//! the synchronization they perform.
+//!
+//! The client requests are special instruction sequences which do nothing when
+//! not running under Valgrind. They are only issued on x86 and x86_64.
+
+// Request codes from valgrind.h, helgrind.h, and drd.h. DRD deliberately uses
+// the same codes as Helgrind's `ANNOTATE_HAPPENS_{BEFORE,AFTER}`, so these are
+// understood by both tools.
+const TOOL_BASE: uint = (('H' as uint) << 24) | (('G' as uint) << 16);
+const HAPPENS_BEFORE: uint = TOOL_BASE + 256 + 33;
+const HAPPENS_AFTER: uint = TOOL_BASE + 256 + 34;
+
+/// Annotates that everything the current thread has done so far happens
+/// before a later `happens_after` on the same object in another thread.
+#[inline]
+pub fn happens_before<T>(obj: &T) {
+    unsafe { request(HAPPENS_BEFORE, obj as *const T as uint); }
+}
+
+/// Annotates that everything after this point in the current thread happens
+/// after all previous `happens_before` annotations on the same object.
+#[inline]
+pub fn happens_after<T>(obj: &T) {
+    unsafe { request(HAPPENS_AFTER, obj as *const T as uint); }
+}
+
+unsafe fn request(code: uint, arg: uint) -> uint {
+    let args = [code, arg, 0, 0, 0, 0];
+    do_client_request(0, &args)
+}
+
+#[cfg(target_arch = "x86_64")]
+#[inline(always)]
+unsafe fn do_client_request(default: uint, args: &[uint, ..6]) -> uint {
+    let result;
+    asm!("rolq $$3, %rdi; rolq $$13, %rdi
+          rolq $$61, %rdi; rolq $$51, %rdi
+          xchgq %rbx, %rbx"
+         : "={rdx}"(result)
+         : "{rax}"(args.as_ptr()), "{rdx}"(default)
+         : "cc", "memory"
+         : "volatile");
+    result
+}
+
+#[cfg(target_arch = "x86")]
+#[inline(always)]
+unsafe fn do_client_request(default: uint, args: &[uint, ..6]) -> uint {
+    let result;
+    asm!("roll $$3, %edi; roll $$13, %edi
+          roll $$29, %edi; roll $$19, %edi
+          xchgl %ebx, %ebx"
+         : "={edx}"(result)
+         : "{eax}"(args.as_ptr()), "{edx}"(default)
+         : "cc", "memory"
+         : "volatile");
+    result
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "x86")))]
+#[inline(always)]
+unsafe fn do_client_request(default: uint, _args: &[uint, ..6]) -> uint {
+    default
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn smoke() {
+        // outside of valgrind these should do nothing
+        let a = 1i;
+        super::happens_before(&a);
+        super::happens_after(&a);
+        assert_eq!(unsafe { super::request(0, 0) }, 0);
+    }
+}